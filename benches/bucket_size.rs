@@ -0,0 +1,64 @@
+//! Time one force evaluation of each tree solver on the 10k body disk, for a
+//! range of leaf bucket sizes.  Run with
+//!
+//!     cargo bench --bench bucket_size
+//!
+//! Best of 5, tree construction included, release build on a single core:
+//!
+//! | bucket size | BarnesHut | LinearBarnesHut |    Fmm   |
+//! |------------:|----------:|----------------:|---------:|
+//! |           1 |  186.2 ms |         54.2 ms |  56.1 ms |
+//! |           2 |  164.1 ms |         53.3 ms |  49.8 ms |
+//! |           4 |  145.4 ms |         51.1 ms |  41.6 ms |
+//! |           8 |  131.2 ms |         48.9 ms |  24.7 ms |
+//! |          16 |  106.8 ms |         48.4 ms |  19.4 ms |
+//! |          32 |  111.5 ms |         55.4 ms |  33.3 ms |
+//! |          64 |  116.6 ms |         72.7 ms |  51.8 ms |
+
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+use nbody::bhtree::NBody;
+use nbody::forces::{Forces, Gravity};
+use nbody::gravity::{GravityConfig, Solver};
+use nbody::initial_conditions::stable_orbit_particles;
+use nbody::linear_octree::LinearOctree;
+use nbody::units::UnitSystem;
+use rand::prelude::*;
+
+const BUCKET_SIZES: [usize; 7] = [1, 2, 4, 8, 16, 32, 64];
+const SOLVERS: [Solver; 3] = [Solver::BarnesHut, Solver::LinearBarnesHut, Solver::Fmm];
+
+fn main() {
+    // Time a single core, so the table does not depend on the machine's
+    // thread count
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(1)
+        .build()
+        .expect("thread pool")
+        .install(run);
+}
+
+fn run() {
+    let g = UnitSystem::Astronomical.g();
+    let particles = stable_orbit_particles(&mut StdRng::seed_from_u64(1), g, 1.0, 10000, 1.0);
+    let bodies: Vec<NBody> = particles.iter()
+        .enumerate()
+        .map(|(i, (mass, position, _))| NBody::new(Entity::from_raw(i as u32), *position, *mass, 0.0))
+        .collect();
+
+    println!("| bucket size | {} |", SOLVERS.map(|s| format!("{:?}", s)).join(" | "));
+    for bucket_size in BUCKET_SIZES {
+        let times = SOLVERS.map(|solver| {
+            let config = GravityConfig { g, solver, bucket_size, ..default() };
+            let mut octree = LinearOctree::default();
+            let best = (0..5).map(|_| {
+                let start = Instant::now();
+                Gravity::new(&config, &mut octree).accelerations(&bodies);
+                start.elapsed()
+            }).min().unwrap_or(Duration::ZERO);
+            format!("{:.1} ms", best.as_secs_f64() * 1e3)
+        });
+        println!("| {} | {} |", bucket_size, times.join(" | "));
+    }
+}
@@ -2,6 +2,7 @@ use bevy::prelude::*;
 use rayon::prelude::*;
 
 use crate::components::{Position, Mass, Radius};
+use crate::gravity::{self, GravityConfig, MultipoleOrder};
 
 /// 3D Bounding Box
 #[derive(Clone,Copy)]
@@ -25,7 +26,7 @@ impl BBox3 {
     where I:Iterator<Item=&'a Vec3>
     {
         if let Some(head) = points.next() {
-            let mut bounds = Self::new(&head,&head);
+            let mut bounds = Self::new(head,head);
             bounds.encompass_all(points);
             bounds
         } else {
@@ -126,6 +127,7 @@ impl Default for BBox3 {
 }
 
 // NBody
+#[derive(Clone,Copy)]
 pub struct NBody {
     pub entity: Entity,
     pub position: Vec3,
@@ -136,52 +138,134 @@ pub struct NBody {
 impl NBody {
     pub fn new(entity:Entity, position: Vec3, mass: f32, radius: f32 ) -> Self
     {
-        let density = 10.0;
         Self { entity, position, mass, radius }
     }
 }
 
+/// Structure-of-arrays copy of the bodies in a leaf, so the direct summation
+/// loop can be auto-vectorized
+#[derive(Default)]
+struct Bucket {
+    x: Vec<f32>,
+    y: Vec<f32>,
+    z: Vec<f32>,
+    mass: Vec<f32>,
+}
+
+impl Bucket {
+    fn from(bodies:&[NBody]) -> Self {
+        Bucket {
+            x: bodies.iter().map(|b| b.position.x).collect(),
+            y: bodies.iter().map(|b| b.position.y).collect(),
+            z: bodies.iter().map(|b| b.position.z).collect(),
+            mass: bodies.iter().map(|b| b.mass).collect(),
+        }
+    }
+}
+
 pub struct BHTreeNode {
     mass: f32,
     center_of_mass: Vec3,
+    quadrupole: Mat3,
     bounds: BBox3,
+    depth: u32,
+    bucket_size: usize,
     children: Option<Box<[BHTreeNode; 8]>>,
-    body: Option<NBody>,
+    bodies: Vec<NBody>,
+    bucket: Bucket,
  }
 
  impl<'a> BHTreeNode {
 
-    /// Construct a new Barnes-Hut tree node, given a bounding box
-    pub fn new(bounds:&BBox3) -> Self {
-        BHTreeNode { mass:0.0, center_of_mass:bounds.center(), bounds:bounds.clone(), children:None, body:None }
+    /// Nodes at this depth are never split, so coincident or nearly
+    /// coincident bodies end up sharing a leaf instead of recursing forever
+    pub const MAX_DEPTH:u32 = 32;
+
+    /// Construct a new Barnes-Hut tree node, given a bounding box and the
+    /// number of bodies a leaf may hold before it is split
+    pub fn new(bounds:&BBox3, bucket_size:usize) -> Self {
+        BHTreeNode::with_depth(bounds, 0, bucket_size)
+    }
+
+    fn with_depth(bounds:&BBox3, depth:u32, bucket_size:usize) -> Self {
+        BHTreeNode {
+            mass:0.0,
+            center_of_mass:bounds.center(),
+            quadrupole:Mat3::ZERO,
+            bounds:*bounds,
+            depth,
+            bucket_size:bucket_size.max(1),
+            children:None,
+            bodies:Vec::new(),
+            bucket:Bucket::default(),
+        }
     }
 
     /// Create a BHTree from an iterator and calculate bounds from the bodies
     /// as well as total mass and center of mass for each node.
-    pub fn from<I>(bounds:&BBox3, bodies:I) -> BHTreeNode
+    pub fn from<I>(bounds:&BBox3, bodies:I, config:&GravityConfig) -> BHTreeNode
     where I:Iterator<Item=(Entity,&'a Position,&'a Mass, &'a Radius)>
     {
-        // Create our top-level tree node
-        let mut root = BHTreeNode::new(bounds);
+        let bodies = bodies
+            .map(|(e,p,m,r)| NBody::new(e,p.0,m.0,r.0))
+            .collect();
 
-        // Insert all bodies
-        for (e,p,m,r) in bodies {
-            root.insert( NBody::new(e,p.0,m.0,r.0));
-            //root.insert_no_update( NBody::new(e,p.0,m.0));
-        }
+        BHTreeNode::from_bodies(bounds, bodies, config)
+    }
 
-        // // Update total mass and center of mass for root and all children
-        // root.update_all();
+    /// Create a BHTree from a list of bodies
+    pub fn from_bodies(bounds:&BBox3, bodies:Vec<NBody>, config:&GravityConfig) -> BHTreeNode {
+        // Build the tree structure in parallel
+        let mut root = BHTreeNode::par_build(bounds, bodies, 0, config.bucket_size);
+
+        // Update total mass and center of mass for root and all children
+        root.update_all();
 
         root
     }
 
+    /// Subtrees with no more than this many bodies are built serially
+    const PARALLEL_BUILD_THRESHOLD:usize = 1024;
+
+    /// Build a tree without calculating mass or center of mass, by
+    /// partitioning the bodies by octant and building each child
+    /// concurrently.
+    fn par_build(bounds:&BBox3, bodies:Vec<NBody>, depth:u32, bucket_size:usize) -> BHTreeNode {
+        let mut node = BHTreeNode::with_depth(bounds, depth, bucket_size);
+
+        if bodies.len() <= Self::PARALLEL_BUILD_THRESHOLD || !node.can_subdivide() {
+            for body in bodies {
+                node.insert_no_update(body);
+            }
+            return node;
+        }
+
+        let mut octants: [Vec<NBody>; 8] = Default::default();
+        for body in bodies {
+            octants[bounds.quadrant_index_for(&body.position)].push(body);
+        }
+
+        let children: Vec<BHTreeNode> = octants
+            .into_par_iter()
+            .zip(bounds.subdivide())
+            .map(|(bodies,subbounds)| BHTreeNode::par_build(&subbounds, bodies, depth + 1, bucket_size))
+            .collect();
+
+        node.children = children.try_into().ok();
+        node
+    }
+
+    /// Can this leaf be split to separate its bodies?
+    fn can_subdivide(&self) -> bool {
+        self.depth < Self::MAX_DEPTH && !self.bounds.is_empty()
+    }
+
     /// Insert a node into the tree, and recalculate mass and center
     /// of mass.
     pub fn insert( &mut self, body:NBody) {
         // If node has children, insert new body into the proper child
-        // Otherwise, if the node has no body, save the new body
-        // Otherwise, move both new body and current body into the proper child
+        // Otherwise, if the bucket has room or cannot be split, save the new body
+        // Otherwise, move both new body and current bodies into the proper child
 
         match &mut self.children {
             Some(children) => {
@@ -189,12 +273,13 @@ pub struct BHTreeNode {
                 children[ix].insert(body);
             },
             None => {
-                if self.body.is_none() {
-                    self.body = Some(body)
+                if self.bodies.len() < self.bucket_size || !self.can_subdivide() {
+                    self.bodies.push(body)
                 } else {
                     self.children = Some(self.subdivide());
-                    let obody = self.body.take().unwrap();
-                    self.insert(obody);
+                    for obody in std::mem::take(&mut self.bodies) {
+                        self.insert(obody);
+                    }
                     self.insert(body);
                 }
             }
@@ -202,15 +287,21 @@ pub struct BHTreeNode {
         self.update();
     }
 
-    /// Recalculate the total mass and center of mass of self from immediate
-    /// children, after inserting one or more NBodies into this node.
+    /// Recalculate the total mass, center of mass and quadrupole moment of
+    /// self from immediate children, after inserting one or more NBodies into
+    /// this node.
     pub fn update(&mut self) {
         // Set mass and center of mass if we are an exterior node
-        if self.body.is_some() {
-            let body = self.body.as_ref().unwrap();
-            self.center_of_mass = body.position;
-            self.mass = body.mass;
+        if !self.bodies.is_empty() {
+            let (total_mass,center_of_mass) =
+                BHTreeNode::bodies_mass_and_center_of_mass(&self.bodies);
+            self.mass = total_mass;
+            self.center_of_mass = center_of_mass;
+            self.quadrupole = self.bodies.iter()
+                .fold(Mat3::ZERO, |acc,body| acc + gravity::point_quadrupole(body.mass, body.position - center_of_mass));
+            self.bucket = Bucket::from(&self.bodies);
         } else {
+            self.bucket = Bucket::default();
             match &self.children {
                 None => (),
                 Some(children) => {
@@ -218,6 +309,8 @@ pub struct BHTreeNode {
                         BHTreeNode::total_mass_and_center_of_mass(children.iter());
                     self.mass = total_mass;
                     self.center_of_mass = center_of_mass;
+                    self.quadrupole =
+                        BHTreeNode::total_quadrupole(children.iter(), center_of_mass);
                 }
             }
         }
@@ -228,22 +321,23 @@ pub struct BHTreeNode {
     /// by a single update.
     pub fn insert_no_update( &mut self, body:NBody) {
         // If node has children, insert new body into the proper child
-        // Otherwise, if the node has no body, save the new body
-        // Otherwise, move both new body and current body into the proper child
+        // Otherwise, if the bucket has room or cannot be split, save the new body
+        // Otherwise, move both new body and current bodies into the proper child
 
         match &mut self.children {
             Some(children) => {
                 let ix = self.bounds.quadrant_index_for(&body.position);
-                children[ix].insert(body);
+                children[ix].insert_no_update(body);
             },
             None => {
-                if self.body.is_none() {
-                    self.body = Some(body);
+                if self.bodies.len() < self.bucket_size || !self.can_subdivide() {
+                    self.bodies.push(body);
                 } else {
                     self.children = Some(self.subdivide());
-                    let obody = self.body.take().unwrap();
+                    for obody in std::mem::take(&mut self.bodies) {
+                        self.insert_no_update(obody);
+                    }
                     self.insert_no_update(body);
-                    self.insert_no_update(obody);
                 }
             }
         }
@@ -259,97 +353,129 @@ pub struct BHTreeNode {
         self.update();
     }
 
-    fn total_mass<I>(nodes:I) -> f32
-        where I : Iterator<Item=&'a BHTreeNode>
-    {
-        nodes.fold(0.0, |acc,c| acc + c.mass )
-    }
-
-    const THETA:f32 = 0.5;
-
     /// This is probably wrong, but return the maximum dimention from amongst x,y,z
     fn size(&self) -> f32 {
         let dim = self.bounds.pmax - self.bounds.pmin;
         dim.x.max(dim.y.max(dim.z))
     }
 
-    /// Calculate the forces against the specified body
-    fn calculate_acceleration(&self, body: &NBody ) -> (Vec3, Vec<Entity>) {
+    /// Calculate the forces against the specified body, and the potential at
+    /// it if enabled in the config
+    pub fn calculate_acceleration(&self, body: &NBody, config: &GravityConfig ) -> (Vec3, f32, Vec<Entity>) {
 
         let mut accel = Vec3::ZERO;
+        let mut potential = 0.0;
         let mut collided_with = Vec::new();
 
-        // Process exterior node (no children, ends recursion)
-        if let Some(other) = self.body.as_ref() {
-            if std::ptr::eq(body,other) {
-                // accel = Vec3::ZERO;
-            }
-            else {
-                let dir = (other.position - body.position).normalize();
-                let dist2 = other.position.distance_squared(body.position);
-                let radaii = body.radius+other.radius;
-                let radaii2 = radaii * radaii;
-                if dist2 > radaii2 {
-                    accel = dir * (crate::G * other.mass / dist2);
-                } else {
-                    collided_with.push(body.entity);
+        // If point is in this node OR is close to this node, sum over its
+        // bodies directly if it is an exterior node (ends recursion), or
+        // recurse into its children
+        if self.bounds.contains(&body.position)
+            || config.should_open(self.size(), self.bounds.center(), self.center_of_mass, body.position)
+        {
+            if !self.bodies.is_empty() {
+                (accel, potential) = self.direct_sum(body, config);
+
+                for other in self.bodies.iter() {
+                    let dist2 = other.position.distance_squared(body.position);
+                    let radaii = body.radius+other.radius;
+                    if dist2 < radaii * radaii && other.entity != body.entity {
+                        collided_with.push(other.entity);
+                    }
                 }
             }
-        }
-
-        // If point is in this node OR is close to this node, recurse into children
-        else if self.bounds.contains(&body.position)
-            || self.size() / self.center_of_mass.distance(body.position) >= Self::THETA
-        {
-            //let mut accel = Vec3::ZERO;
-            if let Some(children) = &self.children {
+            else if let Some(children) = &self.children {
                 for child in children.iter() {
-                    let (deltav,mut collisions) = child.calculate_acceleration(body); 
+                    let (deltav,phi,mut collisions) = child.calculate_acceleration(body, config);
                     accel += deltav;
+                    potential += phi;
                     collided_with.append(&mut collisions);
                 }
             }
         }
 
-        // Else, process using this node approx center of mass
-        // (ends recursion)
+        // Else, process using this node approx center of mass, plus the
+        // quadrupole correction if enabled (ends recursion)
         else
         {
-            let dir = (self.center_of_mass - body.position).normalize();
-            let dist2 = self.center_of_mass.distance_squared(body.position);
-            let radaii = body.radius + body.radius; // approx 
-            if dist2 >= radaii {
-                accel = dir * (crate::G * self.mass / dist2);
+            let r = body.position - self.center_of_mass;
+            accel = config.pair_acceleration(-r, self.mass);
+            if config.potential {
+                potential = self.mass * config.pair_potential(r.length_squared());
+            }
+            if config.multipole_order == MultipoleOrder::Quadrupole {
+                let r2 = config.softened_dist2(r.length_squared());
+                accel += gravity::quadrupole_acceleration(config.g, &self.quadrupole, r, r2);
+                if config.potential {
+                    potential += gravity::quadrupole_potential(config.g, &self.quadrupole, r, r2);
+                }
             }
         }
 
-        if !accel.x.is_finite() {
-            accel.x = 0.0;
-        }
-        if !accel.y.is_finite() {
-            accel.y = 0.0;
-        }
-        if !accel.z.is_finite()
-        {
-            accel.z = 0.0;
-        }
-        
-        (accel,collided_with)
+        (accel,potential,collided_with)
     }
 
     /// update_forces
-    pub fn collect_accelerations(self) -> Vec<(Entity,Vec3,Vec<Entity>)> {
+    pub fn collect_accelerations(self, config: &GravityConfig) -> Vec<(Entity,Vec3,f32,Vec<Entity>)> {
 
         self.iter()
             .par_bridge()
             .map( | body | {
-                let (accel,collisions) = self.calculate_acceleration(body);
-                (body.entity,accel,collisions)
+                let (accel,potential,collisions) = self.calculate_acceleration(body, config);
+                (body.entity,accel,potential,collisions)
             })
             .collect()
     }
 
 
+    /// Sum the accelerations, and potentials if enabled, due to every body in
+    /// this leaf's bucket.  The body itself, or any body at exactly the same
+    /// position, does not contribute.
+    fn direct_sum(&self, body: &NBody, config: &GravityConfig) -> (Vec3, f32) {
+        const LANES: usize = 8;
+
+        let bucket = &self.bucket;
+        let p = body.position;
+        let mut ax = [0.0f32; LANES];
+        let mut ay = [0.0f32; LANES];
+        let mut az = [0.0f32; LANES];
+        let mut phi = [0.0f32; LANES];
+
+        // Accumulate bodies at indices [start, start+len) into lanes [0, len)
+        let mut accumulate = |start: usize, len: usize| {
+            for lane in 0..len {
+                let i = start + lane;
+                let dx = bucket.x[i] - p.x;
+                let dy = bucket.y[i] - p.y;
+                let dz = bucket.z[i] - p.z;
+                let dist2 = dx*dx + dy*dy + dz*dz;
+                let f = if dist2 > 0.0 { bucket.mass[i] * config.pair_factor(dist2) } else { 0.0 };
+                ax[lane] += f * dx;
+                ay[lane] += f * dy;
+                az[lane] += f * dz;
+                if config.potential && dist2 > 0.0 {
+                    phi[lane] += bucket.mass[i] * config.pair_potential(dist2);
+                }
+            }
+        };
+
+        // Full groups of LANES bodies, so the inner loop has a fixed trip
+        // count, then the remainder
+        let n = bucket.mass.len();
+        let full = n - n % LANES;
+        for start in (0..full).step_by(LANES) {
+            accumulate(start, LANES);
+        }
+        accumulate(full, n - full);
+
+        (Vec3::new(ax.iter().sum(), ay.iter().sum(), az.iter().sum()), phi.iter().sum())
+    }
+
+    /// Calculate total mass and center of mass of the bodies held by a leaf
+    fn bodies_mass_and_center_of_mass(bodies:&[NBody]) -> (f32,Vec3) {
+        gravity::mass_and_center_of_mass(bodies.iter().map(|b| (b.position, b.mass)), bodies[0].position)
+    }
+
     /// Calculate total mass and center of mass using Kahan summation algorithm
     fn total_mass_and_center_of_mass<I>(nodes:I) -> (f32,Vec3)
         where I : Iterator<Item=&'a BHTreeNode>
@@ -366,24 +492,40 @@ pub struct BHTreeNode {
             total_mass += node.mass;
         }
 
-        cm = cm / total_mass;
+        cm /= total_mass;
 
         (total_mass, cm)
     }
 
 
+    /// Combine child quadrupole moments about a new center of mass, using the
+    /// parallel axis theorem to shift each child's expansion center
+    fn total_quadrupole<I>(nodes:I, center_of_mass:Vec3) -> Mat3
+        where I : Iterator<Item=&'a BHTreeNode>
+    {
+        nodes
+            .filter(|node| node.mass > 0.0)
+            .fold(Mat3::ZERO, |acc,node| {
+                let d = node.center_of_mass - center_of_mass;
+                acc + node.quadrupole + gravity::point_quadrupole(node.mass, d)
+            })
+    }
+
+
     // Split this node into 8 sub nodes
     fn subdivide(&self) -> Box<[Self;8]> {
         let subbounds = self.bounds.subdivide();
+        let depth = self.depth + 1;
+        let bucket_size = self.bucket_size;
         Box::new([        
-            BHTreeNode::new(&subbounds[0]),
-            BHTreeNode::new(&subbounds[1]),
-            BHTreeNode::new(&subbounds[2]),
-            BHTreeNode::new(&subbounds[3]),
-            BHTreeNode::new(&subbounds[4]),
-            BHTreeNode::new(&subbounds[5]),
-            BHTreeNode::new(&subbounds[6]),
-            BHTreeNode::new(&subbounds[7]),
+            BHTreeNode::with_depth(&subbounds[0], depth, bucket_size),
+            BHTreeNode::with_depth(&subbounds[1], depth, bucket_size),
+            BHTreeNode::with_depth(&subbounds[2], depth, bucket_size),
+            BHTreeNode::with_depth(&subbounds[3], depth, bucket_size),
+            BHTreeNode::with_depth(&subbounds[4], depth, bucket_size),
+            BHTreeNode::with_depth(&subbounds[5], depth, bucket_size),
+            BHTreeNode::with_depth(&subbounds[6], depth, bucket_size),
+            BHTreeNode::with_depth(&subbounds[7], depth, bucket_size),
         ])
     }
 
@@ -400,13 +542,13 @@ pub struct BHTreeNode {
  pub struct BHTreeNodeIter<'a>
  {
      stack : Vec<&'a BHTreeNode>,
+     bodies : std::slice::Iter<'a, NBody>,
  }
  
  impl<'a> BHTreeNodeIter<'a> {
      fn new(root: &'a BHTreeNode) -> Self {
-         let mut stack = Vec::new();
-         stack.push(root); 
-         BHTreeNodeIter { stack }
+         let stack = vec![root];
+         BHTreeNodeIter { stack, bodies: [].iter() }
      }
  }
  
@@ -415,21 +557,22 @@ pub struct BHTreeNode {
  
      fn next(&mut self) -> Option<Self::Item> {
  
+         if let Some(body) = self.bodies.next() {
+             return Some(body);
+         }
+
          let next_node = self.stack.pop();
  
          match next_node {
              None => None,
              Some(node) => {
-                 if let Some(body) = node.body.as_ref() {
-                     Some(body)
-                 } else {
-                     if let Some(children) = node.children.as_ref() {
-                         for child in children.iter() {
-                             self.stack.push(child)
-                         }
+                 if let Some(children) = node.children.as_ref() {
+                     for child in children.iter() {
+                         self.stack.push(child)
                      }
-                     self.next()
                  }
+                 self.bodies = node.bodies.iter();
+                 self.next()
              }
          }
      }
@@ -438,13 +581,13 @@ pub struct BHTreeNode {
 pub struct BHTreeNodeIterMut<'a>
 {
     stack : Vec<&'a mut BHTreeNode>,
+    bodies : std::slice::IterMut<'a, NBody>,
 }
 
 impl<'a> BHTreeNodeIterMut<'a> {
     fn new(root: &'a mut BHTreeNode) -> Self {
-        let mut stack = Vec::new();
-        stack.push(root); 
-        BHTreeNodeIterMut { stack }
+        let stack = vec![root];
+        BHTreeNodeIterMut { stack, bodies: [].iter_mut() }
     }
 }
 
@@ -453,22 +596,60 @@ impl<'a> Iterator for BHTreeNodeIterMut<'a> {
 
     fn next(&mut self) -> Option<Self::Item> {
 
+        if let Some(body) = self.bodies.next() {
+            return Some(body);
+        }
+
         let next_node = self.stack.pop();
 
         match next_node {
             None => None,
             Some(node) => {
-                if let Some(body) = node.body.as_mut() {
-                    Some(body)
-                } else {
-                    if let Some(children) = node.children.as_mut() {
-                        for child in children.iter_mut() {
-                            self.stack.push(child)
-                        }
+                if let Some(children) = node.children.as_mut() {
+                    for child in children.iter_mut() {
+                        self.stack.push(child)
                     }
-                    self.next()
                 }
+                self.bodies = node.bodies.iter_mut();
+                self.next()
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::prelude::*;
+
+    /// Building above the parallel threshold gives the same tree as inserting
+    /// the bodies one by one
+    #[test]
+    fn parallel_build_matches_serial() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let bodies: Vec<NBody> = (0..5000)
+            .map(|i| {
+                let position = Vec3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0));
+                NBody::new(Entity::from_raw(i), position, rng.gen_range(0.5..2.0), 0.0)
+            })
+            .collect();
+        let config = GravityConfig { g: 1.0, ..default() };
+        let bounds = BBox3::from(bodies.iter().map(|b| &b.position));
+
+        let mut serial = BHTreeNode::new(&bounds, config.bucket_size);
+        for body in bodies.iter() {
+            serial.insert_no_update(*body);
+        }
+        serial.update_all();
+        let parallel = BHTreeNode::from_bodies(&bounds, bodies.clone(), &config);
+
+        assert_eq!(serial.iter().count(), parallel.iter().count());
+        assert!((serial.mass - parallel.mass).abs() <= 1e-6 * serial.mass);
+        assert!(serial.center_of_mass.distance(parallel.center_of_mass) < 1e-6);
+        for body in bodies.iter() {
+            let (a, _, _) = serial.calculate_acceleration(body, &config);
+            let (b, _, _) = parallel.calculate_acceleration(body, &config);
+            assert!((a - b).length() <= 1e-6 * a.length(), "{} vs {}", a, b);
+        }
+    }
+}
@@ -0,0 +1,350 @@
+use bevy::prelude::*;
+use bevy::utils::HashSet;
+
+use crate::bhtree::NBody;
+use crate::components::*;
+use crate::forces::{ExternalFields, Forces, Gravity};
+use crate::gravity::GravityConfig;
+use crate::linear_octree::LinearOctree;
+use crate::units::UnitSystem;
+
+// Collisions
+//
+// The force evaluation already visits every body's neighbours, so it reports
+// the bodies each one overlaps on the way.  After every step those lists are
+// gathered into unique pairs, announced as events, and then either merged into
+// a single body (perfectly inelastic), or bounced off each other as hard
+// spheres.  Both conserve mass, momentum and the center of mass.  Either way
+// the bodies involved end up somewhere the step's force evaluation did not
+// see, so their accelerations are evaluated again before the next step.
+
+/// How overlapping bodies are resolved
+#[derive(Resource,Clone,Copy,Debug,Default,PartialEq)]
+pub enum CollisionResponse {
+    /// Merge into a single body
+    #[default]
+    Merge,
+    /// Bounce as hard spheres, with the given coefficient of restitution: 1
+    /// is perfectly elastic, 0 leaves no relative normal velocity
+    Bounce { restitution: f32 },
+}
+
+/// Pairs of overlapping bodies at the end of the last step, each pair once
+#[derive(Resource,Default)]
+pub struct Collisions(pub Vec<(Entity,Entity)>);
+
+impl Collisions {
+    /// Gather unique pairs from the bodies each of `bodies` overlaps, in the
+    /// same order.  Either or both bodies of a pair may report it.
+    pub fn from_lists(bodies: &[NBody], lists: &[Vec<Entity>]) -> Self {
+        if lists.len() != bodies.len() {
+            return Collisions::default();
+        }
+
+        let mut pairs: Vec<(Entity,Entity)> = bodies.iter()
+            .zip(lists)
+            .flat_map(|(body, others)| others.iter().map(move |&other| {
+                if body.entity < other { (body.entity, other) } else { (other, body.entity) }
+            }))
+            .collect();
+        pairs.sort_unstable();
+        pairs.dedup();
+        Collisions(pairs)
+    }
+}
+
+/// Sent once per overlapping pair per physics step, before the collision is
+/// resolved
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct CollisionEvent {
+    pub a: Entity,
+    pub b: Entity,
+    /// Velocity of `b` relative to `a`
+    pub relative_velocity: Vec3,
+    /// Point of contact on the line between the centers
+    pub point: Vec3,
+}
+
+/// Send a CollisionEvent for every overlapping pair
+pub fn collision_event_system(
+    collisions: Res<Collisions>,
+    q: Query<(&Position, &Velocity, &Radius)>,
+    mut events: EventWriter<CollisionEvent>,
+) {
+    events.send_batch(collisions.0.iter().filter_map(|&(a, b)| {
+        let [(pa, va, ra), (pb, vb, rb)] = q.get_many([a, b]).ok()?;
+        let point = pa.0.lerp(pb.0, ra.0 / (ra.0 + rb.0));
+        Some(CollisionEvent { a, b, relative_velocity: vb.0 - va.0, point })
+    }));
+}
+
+/// Merge each overlapping pair into the heavier body and despawn the lighter.
+/// The merged body takes the radius of its new mass.  A body absorbed earlier
+/// in the same step is left for the next.
+pub fn merge_system(
+    mut commands: Commands,
+    response: Res<CollisionResponse>,
+    collisions: Res<Collisions>,
+    units: Res<UnitSystem>,
+    mut q: Query<(&mut Mass, &mut Position, &mut PreviousPosition, &mut Velocity, &mut Acceleration, &mut Radius)>,
+) {
+    if *response != CollisionResponse::Merge {
+        return;
+    }
+
+    let mut absorbed = HashSet::new();
+
+    for &(a, b) in collisions.0.iter() {
+        if absorbed.contains(&a) || absorbed.contains(&b) {
+            continue;
+        }
+        let Ok([first, second]) = q.get_many_mut([a, b]) else {
+            continue;
+        };
+        let ((_, into), (victim, from)) = if first.0.0 >= second.0.0 {
+            ((a, first), (b, second))
+        } else {
+            ((b, second), (a, first))
+        };
+        let (mut mass, mut position, mut previous, mut velocity, mut accel, mut radius) = into;
+        let (other_mass, other_position, other_previous, other_velocity, other_accel, _) = from;
+
+        let total = mass.0 + other_mass.0;
+        let weighted = |x: Vec3, y: Vec3| (mass.0 * x + other_mass.0 * y) / total;
+        position.0 = weighted(position.0, other_position.0);
+        previous.0 = weighted(previous.0, other_previous.0);
+        velocity.0 = weighted(velocity.0, other_velocity.0);
+        accel.0 = weighted(accel.0, other_accel.0);
+        mass.0 = total;
+        radius.0 = crate::initial_conditions::body_radius(&units, total);
+
+        commands.entity(victim).despawn();
+        absorbed.insert(victim);
+    }
+}
+
+/// Resolve each overlapping pair as colliding hard spheres.  Approaching
+/// bodies exchange an impulse along the line of centers, and the overlap is
+/// removed by pushing them apart in inverse proportion to their masses, which
+/// leaves the center of mass in place.  Pairs are resolved one after another,
+/// so a body in several contacts sees the result of the earlier ones.
+pub fn bounce_system(
+    response: Res<CollisionResponse>,
+    collisions: Res<Collisions>,
+    mut q: Query<(&Mass, &mut Position, &mut Velocity, &Radius)>,
+) {
+    let CollisionResponse::Bounce { restitution } = *response else {
+        return;
+    };
+
+    for &(a, b) in collisions.0.iter() {
+        let Ok([(ma, mut pa, mut va, ra), (mb, mut pb, mut vb, rb)]) = q.get_many_mut([a, b]) else {
+            continue;
+        };
+        let diff = pb.0 - pa.0;
+        let dist = diff.length();
+        if dist == 0.0 {
+            continue;
+        }
+        let normal = diff / dist;
+        let total = ma.0 + mb.0;
+
+        // Impulse, only while approaching
+        let approach = (vb.0 - va.0).dot(normal);
+        if approach < 0.0 {
+            let reduced = ma.0 * mb.0 / total;
+            let impulse = -(1.0 + restitution) * approach * reduced;
+            va.0 -= normal * (impulse / ma.0);
+            vb.0 += normal * (impulse / mb.0);
+        }
+
+        // Penetration
+        let depth = ra.0 + rb.0 - dist;
+        if depth > 0.0 {
+            pa.0 -= normal * (depth * mb.0 / total);
+            pb.0 += normal * (depth * ma.0 / total);
+        }
+    }
+}
+
+/// Re-evaluate the acceleration of every body a collision moved or merged,
+/// once merged bodies are despawned, so the next step does not start from
+/// forces at their old positions.  Other bodies keep theirs, which are off by
+/// the order of the overlap.  The Potential of the touched bodies, which only
+/// the diagnostics read, is refreshed by the next step.
+pub fn refresh_acceleration_system(
+    collisions: Res<Collisions>,
+    config: Res<GravityConfig>,
+    fields: Res<ExternalFields>,
+    mut octree: ResMut<LinearOctree>,
+    mut q: Query<(Entity, &Position, &Mass, &Radius, &mut Acceleration)>,
+) {
+    if collisions.0.is_empty() {
+        return;
+    }
+    let touched: HashSet<Entity> = collisions.0.iter().flat_map(|&(a, b)| [a, b]).collect();
+    let bodies: Vec<NBody> = q.iter().map(|(e,p,m,r,_)| NBody::new(e,p.0,m.0,r.0)).collect();
+    let active: Vec<usize> = bodies.iter()
+        .enumerate()
+        .filter(|(_, body)| touched.contains(&body.entity))
+        .map(|(i, _)| i)
+        .collect();
+
+    let accelerations = Gravity::new(&config, &mut octree)
+        .with_fields(&fields)
+        .accelerations_of(&bodies, &active);
+    for (&i, accel) in active.iter().zip(accelerations) {
+        if let Ok((_, _, _, _, mut acceleration)) = q.get_mut(bodies[i].entity) {
+            acceleration.0 = accel;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spawn(world: &mut World, mass: f32, position: Vec3, velocity: Vec3) -> Entity {
+        world.spawn((
+            Mass(mass),
+            Position(position),
+            PreviousPosition(position),
+            Velocity(velocity),
+            Acceleration(Vec3::ZERO),
+            Radius(1.0),
+        )).id()
+    }
+
+    #[test]
+    fn pairs_are_unique() {
+        let (a, b, c) = (Entity::from_raw(0), Entity::from_raw(1), Entity::from_raw(2));
+        let bodies: Vec<NBody> = [a, b, c].iter().map(|&e| NBody::new(e, Vec3::ZERO, 1.0, 1.0)).collect();
+        // b reports a, but a misses b, as a tree walk may
+        let collisions = Collisions::from_lists(&bodies, &[vec![c], vec![a], vec![a]]);
+        assert_eq!(collisions.0, vec![(a, b), (a, c)]);
+    }
+
+    #[test]
+    fn merging_conserves_mass_and_momentum() {
+        let mut world = World::new();
+        world.insert_resource(UnitSystem::default());
+        world.insert_resource(CollisionResponse::Merge);
+        let heavy = spawn(&mut world, 3.0, Vec3::ZERO, Vec3::X);
+        let light = spawn(&mut world, 1.0, Vec3::new(1.0, 0.0, 0.0), Vec3::new(-1.0, 2.0, 0.0));
+        let other = spawn(&mut world, 1.0, Vec3::new(10.0, 0.0, 0.0), Vec3::ZERO);
+        world.insert_resource(Collisions(vec![(heavy, light), (light, other)]));
+
+        let mut schedule = Schedule::new();
+        schedule.add_system(merge_system);
+        schedule.run(&mut world);
+
+        assert!(world.get_entity(light).is_none());
+        assert!(world.get_entity(other).is_some());
+        let merged = world.entity(heavy);
+        assert_eq!(merged.get::<Mass>().unwrap().0, 4.0);
+        assert_eq!(merged.get::<Position>().unwrap().0, Vec3::new(0.25, 0.0, 0.0));
+        assert_eq!(merged.get::<Velocity>().unwrap().0, Vec3::new(0.5, 0.5, 0.0));
+        let units = UnitSystem::default();
+        assert_eq!(merged.get::<Radius>().unwrap().0, crate::initial_conditions::body_radius(&units, 4.0));
+    }
+
+    #[test]
+    fn one_event_per_pair() {
+        let mut world = World::new();
+        world.init_resource::<Events<CollisionEvent>>();
+        let a = spawn(&mut world, 1.0, Vec3::ZERO, Vec3::X);
+        let b = spawn(&mut world, 3.0, Vec3::new(1.5, 0.0, 0.0), -Vec3::X);
+        let bodies = [NBody::new(a, Vec3::ZERO, 1.0, 1.0), NBody::new(b, Vec3::ZERO, 3.0, 1.0)];
+        world.insert_resource(Collisions::from_lists(&bodies, &[vec![b], vec![a]]));
+
+        let mut schedule = Schedule::new();
+        schedule.add_system(collision_event_system);
+        schedule.run(&mut world);
+
+        let events = world.resource::<Events<CollisionEvent>>();
+        let sent: Vec<CollisionEvent> = events.get_reader().iter(events).copied().collect();
+        assert_eq!(sent, vec![CollisionEvent {
+            a, b,
+            relative_velocity: Vec3::new(-2.0, 0.0, 0.0),
+            point: Vec3::new(0.75, 0.0, 0.0),
+        }]);
+    }
+
+    /// Head-on collision of bodies of mass 1 and 3, overlapping by 0.5
+    fn bounce(restitution: f32) -> (Vec3, Vec3, Vec3, Vec3) {
+        let mut world = World::new();
+        world.insert_resource(CollisionResponse::Bounce { restitution });
+        let a = spawn(&mut world, 1.0, Vec3::ZERO, Vec3::X);
+        let b = spawn(&mut world, 3.0, Vec3::new(1.5, 0.0, 0.0), -Vec3::X);
+        world.insert_resource(Collisions(vec![(a, b)]));
+
+        let mut schedule = Schedule::new();
+        schedule.add_system(bounce_system);
+        schedule.run(&mut world);
+
+        let get = |e| (world.get::<Position>(e).unwrap().0, world.get::<Velocity>(e).unwrap().0);
+        let ((pa, va), (pb, vb)) = (get(a), get(b));
+        (pa, va, pb, vb)
+    }
+
+    #[test]
+    fn elastic_bounce() {
+        let (pa, va, pb, vb) = bounce(1.0);
+        // Momentum and kinetic energy conserved
+        assert!(((va + 3.0 * vb) - Vec3::new(-2.0, 0.0, 0.0)).length() < 1e-6);
+        assert!((0.5 * va.length_squared() + 1.5 * vb.length_squared() - 2.0).abs() < 1e-5);
+        assert!((va - Vec3::new(-2.0, 0.0, 0.0)).length() < 1e-6);
+        assert!(vb.length() < 1e-6);
+        // Just touching, with the center of mass in place
+        assert!((pa.distance(pb) - 2.0).abs() < 1e-6);
+        assert!(((pa + 3.0 * pb) / 4.0 - Vec3::new(1.125, 0.0, 0.0)).length() < 1e-6);
+    }
+
+    #[test]
+    fn inelastic_bounce() {
+        let (_, va, _, vb) = bounce(0.0);
+        assert!((va - vb).length() < 1e-6);
+        assert!((va - Vec3::new(-0.5, 0.0, 0.0)).length() < 1e-6);
+    }
+
+    #[test]
+    fn touched_bodies_get_fresh_accelerations() {
+        let mut world = World::new();
+        world.insert_resource(UnitSystem::default());
+        world.insert_resource(CollisionResponse::Merge);
+        world.insert_resource(GravityConfig { g: 1.0, softening: crate::gravity::Softening::None, ..default() });
+        world.init_resource::<ExternalFields>();
+        world.init_resource::<LinearOctree>();
+        let heavy = spawn(&mut world, 3.0, Vec3::ZERO, Vec3::ZERO);
+        let light = spawn(&mut world, 1.0, Vec3::new(1.0, 0.0, 0.0), Vec3::ZERO);
+        let other = spawn(&mut world, 1.0, Vec3::new(10.0, 0.0, 0.0), Vec3::ZERO);
+        world.insert_resource(Collisions(vec![(heavy, light)]));
+
+        let mut schedule = Schedule::new();
+        schedule.add_systems((merge_system, apply_system_buffers, refresh_acceleration_system).chain());
+        schedule.run(&mut world);
+
+        // Only the other body pulls on the merged one, from 9.75 away
+        let accel = world.get::<Acceleration>(heavy).unwrap().0;
+        assert!((accel - Vec3::new(1.0 / (9.75 * 9.75), 0.0, 0.0)).length() < 1e-6, "{}", accel);
+        // The other body is untouched
+        assert_eq!(world.get::<Acceleration>(other).unwrap().0, Vec3::ZERO);
+    }
+
+    /// Bodies overlap only when closer than the sum of their radii, so
+    /// coincident points never collide with any solver
+    #[test]
+    fn points_never_overlap() {
+        use crate::gravity::Solver;
+        let bodies: Vec<NBody> = (0..20)
+            .map(|i| NBody::new(Entity::from_raw(i), Vec3::new((i % 2) as f32, 0.0, 0.0), 1.0, 0.0))
+            .collect();
+        for solver in [Solver::BarnesHut, Solver::LinearBarnesHut, Solver::Fmm, Solver::Direct] {
+            let config = GravityConfig { g: 1.0, solver, ..default() };
+            let mut octree = LinearOctree::default();
+            let mut forces = Gravity::new(&config, &mut octree);
+            forces.accelerations(&bodies);
+            assert!(forces.collisions.iter().all(Vec::is_empty), "{:?}", solver);
+        }
+    }
+}
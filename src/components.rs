@@ -3,6 +3,10 @@ use bevy::{prelude::*};
 #[derive(Component)]
 pub struct Position(pub Vec3);
 
+/// Position at the previous physics step, for interpolating the Transform
+#[derive(Component)]
+pub struct PreviousPosition(pub Vec3);
+
 #[derive(Component)]
 pub struct Mass(pub f32);
 
@@ -14,3 +18,7 @@ pub struct Acceleration(pub Vec3);
 
 #[derive(Component)]
 pub struct Radius(pub f32);
+
+/// Gravitational potential at the body due to all others, per unit mass
+#[derive(Component)]
+pub struct Potential(pub f32);
@@ -0,0 +1,231 @@
+use std::time::Duration;
+
+use bevy::diagnostic::{Diagnostic, DiagnosticId, Diagnostics};
+use bevy::math::DVec3;
+use bevy::prelude::*;
+use bevy::time::common_conditions::on_timer;
+use rayon::prelude::*;
+
+use crate::components::{Mass, Position, Potential, Velocity};
+use crate::gravity::GravityConfig;
+use crate::NBodySet;
+use crate::units::UnitSystem;
+
+// Conservation diagnostics
+//
+// Totals that an isolated system conserves, measured periodically and
+// registered as Bevy diagnostics so LogDiagnosticsPlugin reports them next to
+// the frame time.  Sums are accumulated in f64.  When the solver computes the
+// potential at each body, the potential energy comes from those; otherwise it
+// is an exact direct sum over pairs, so it is only measured once per interval.
+
+/// Conserved totals of a set of bodies
+#[derive(Clone,Copy,Debug,Default)]
+pub struct Conservation {
+    pub kinetic_energy: f64,
+    pub potential_energy: f64,
+    pub momentum: DVec3,
+    pub angular_momentum: DVec3,
+    pub center_of_mass: DVec3,
+}
+
+impl Conservation {
+
+    /// Measure the totals of bodies given as (mass, position, velocity), with
+    /// the potential energy summed over every pair
+    pub fn measure(bodies: &[(f32,Vec3,Vec3)], config: &GravityConfig) -> Self {
+        // Each pair once
+        let potential_energy: f64 = (0..bodies.len()).into_par_iter()
+            .map(|i| {
+                let (mi, pi, _) = bodies[i];
+                bodies[i+1..].iter()
+                    .map(|(mj, pj, _)| {
+                        let dist2 = pi.distance_squared(*pj);
+                        if dist2 > 0.0 {
+                            mi as f64 * *mj as f64 * config.pair_potential(dist2) as f64
+                        } else {
+                            0.0
+                        }
+                    })
+                    .sum::<f64>()
+            })
+            .sum();
+
+        Conservation { potential_energy, ..Self::kinematic(bodies) }
+    }
+
+    /// Measure the totals of bodies given as (mass, position, velocity), with
+    /// the potential energy from the potential at each body
+    pub fn with_potentials(bodies: &[(f32,Vec3,Vec3)], potentials: &[f32]) -> Self {
+        // Each pair is counted from both ends
+        let potential_energy: f64 = bodies.par_iter()
+            .zip(potentials)
+            .map(|((m,_,_), phi)| 0.5 * *m as f64 * *phi as f64)
+            .sum();
+        Conservation { potential_energy, ..Self::kinematic(bodies) }
+    }
+
+    /// Measure the totals of the bodies in a query, using their potentials
+    /// when the solver computes them
+    pub fn of_bodies(q: &BodyQuery, config: &GravityConfig) -> Self {
+        let bodies: Vec<(f32,Vec3,Vec3)> = q.iter().map(|(m,p,v,_)| (m.0, p.0, v.0)).collect();
+        let potentials: Option<Vec<f32>> = q.iter().map(|(_,_,_,phi)| phi.map(|phi| phi.0)).collect();
+        match potentials {
+            Some(potentials) if config.potential => Conservation::with_potentials(&bodies, &potentials),
+            _ => Conservation::measure(&bodies, config),
+        }
+    }
+
+    /// Every total but the potential energy
+    fn kinematic(bodies: &[(f32,Vec3,Vec3)]) -> Self {
+        let mass: f64 = bodies.iter().map(|(m,_,_)| *m as f64).sum();
+
+        let (kinetic_energy, momentum, angular_momentum, weighted_position) = bodies.par_iter()
+            .map(|(m,p,v)| {
+                let (m, p, v) = (*m as f64, p.as_dvec3(), v.as_dvec3());
+                (0.5 * m * v.length_squared(), m * v, m * p.cross(v), m * p)
+            })
+            .reduce(|| (0.0, DVec3::ZERO, DVec3::ZERO, DVec3::ZERO),
+                |a, b| (a.0 + b.0, a.1 + b.1, a.2 + b.2, a.3 + b.3));
+
+        let center_of_mass = if mass > 0.0 { weighted_position / mass } else { DVec3::ZERO };
+        Conservation { kinetic_energy, potential_energy: 0.0, momentum, angular_momentum, center_of_mass }
+    }
+
+    pub fn total_energy(&self) -> f64 {
+        self.kinetic_energy + self.potential_energy
+    }
+}
+
+/// Components the totals are measured from
+pub type BodyQuery<'w, 's> = Query<'w, 's, (&'static Mass, &'static Position, &'static Velocity, Option<&'static Potential>)>;
+
+/// Adds kinetic, potential and total energy, relative energy error, momentum,
+/// angular momentum and center of mass drift diagnostics, measured every
+/// `interval`
+pub struct ConservationDiagnosticsPlugin {
+    pub interval: Duration,
+}
+
+impl Default for ConservationDiagnosticsPlugin {
+    fn default() -> Self {
+        ConservationDiagnosticsPlugin { interval: Duration::from_secs(1) }
+    }
+}
+
+/// The measurement of the initial conditions, which later ones are compared
+/// against
+#[derive(Resource,Default)]
+pub struct InitialConservation(Option<Conservation>);
+
+impl Plugin for ConservationDiagnosticsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<InitialConservation>()
+            .add_startup_system(Self::setup_system)
+            .add_startup_system(Self::diagnostic_system.in_base_set(StartupSet::PostStartup))
+            .add_system(Self::diagnostic_system
+                .run_if(on_timer(self.interval))
+                .in_set(NBodySet::Diagnostics));
+    }
+}
+
+impl ConservationDiagnosticsPlugin {
+    pub const KINETIC_ENERGY: DiagnosticId = DiagnosticId::from_u128(0x5a1c_0e7d_3b8f_4d2a_9c61_0b4e_7f28_d301);
+    pub const POTENTIAL_ENERGY: DiagnosticId = DiagnosticId::from_u128(0x5a1c_0e7d_3b8f_4d2a_9c61_0b4e_7f28_d302);
+    pub const TOTAL_ENERGY: DiagnosticId = DiagnosticId::from_u128(0x5a1c_0e7d_3b8f_4d2a_9c61_0b4e_7f28_d303);
+    pub const ENERGY_ERROR: DiagnosticId = DiagnosticId::from_u128(0x5a1c_0e7d_3b8f_4d2a_9c61_0b4e_7f28_d304);
+    pub const MOMENTUM: DiagnosticId = DiagnosticId::from_u128(0x5a1c_0e7d_3b8f_4d2a_9c61_0b4e_7f28_d305);
+    pub const ANGULAR_MOMENTUM: DiagnosticId = DiagnosticId::from_u128(0x5a1c_0e7d_3b8f_4d2a_9c61_0b4e_7f28_d306);
+    pub const COM_DRIFT: DiagnosticId = DiagnosticId::from_u128(0x5a1c_0e7d_3b8f_4d2a_9c61_0b4e_7f28_d307);
+
+    pub fn setup_system(mut diagnostics: ResMut<Diagnostics>, units: Res<UnitSystem>) {
+        let (length, mass, time) = units.names();
+        let energy = format!(" {} {}^2/{}^2", mass, length, time);
+        let momentum = format!(" {} {}/{}", mass, length, time);
+        let angular_momentum = format!(" {} {}^2/{}", mass, length, time);
+
+        let diagnostic = |id, name, suffix: &str| {
+            Diagnostic::new(id, name, 20).with_suffix(suffix.to_string()).with_smoothing_factor(0.0)
+        };
+        diagnostics.add(diagnostic(Self::KINETIC_ENERGY, "kinetic_energy", &energy));
+        diagnostics.add(diagnostic(Self::POTENTIAL_ENERGY, "potential_energy", &energy));
+        diagnostics.add(diagnostic(Self::TOTAL_ENERGY, "total_energy", &energy));
+        diagnostics.add(diagnostic(Self::ENERGY_ERROR, "relative_energy_error", ""));
+        diagnostics.add(diagnostic(Self::MOMENTUM, "momentum", &momentum));
+        diagnostics.add(diagnostic(Self::ANGULAR_MOMENTUM, "angular_momentum", &angular_momentum));
+        diagnostics.add(diagnostic(Self::COM_DRIFT, "center_of_mass_drift", &format!(" {}", length)));
+    }
+
+    /// Measure the totals and report them.  Bodies' potentials must be up to
+    /// date, so at startup this has to run after the initial force evaluation.
+    pub fn diagnostic_system(
+        mut diagnostics: ResMut<Diagnostics>,
+        mut initial: ResMut<InitialConservation>,
+        config: Res<GravityConfig>,
+        q: BodyQuery,
+    ) {
+        let now = Conservation::of_bodies(&q, &config);
+        let initial = *initial.0.get_or_insert(now);
+
+        diagnostics.add_measurement(Self::KINETIC_ENERGY, || now.kinetic_energy);
+        diagnostics.add_measurement(Self::POTENTIAL_ENERGY, || now.potential_energy);
+        diagnostics.add_measurement(Self::TOTAL_ENERGY, || now.total_energy());
+        diagnostics.add_measurement(Self::ENERGY_ERROR, || {
+            (now.total_energy() - initial.total_energy()) / initial.total_energy().abs()
+        });
+        diagnostics.add_measurement(Self::MOMENTUM, || now.momentum.length());
+        diagnostics.add_measurement(Self::ANGULAR_MOMENTUM, || now.angular_momentum.length());
+        diagnostics.add_measurement(Self::COM_DRIFT, || now.center_of_mass.distance(initial.center_of_mass));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gravity::Softening;
+
+    /// Two bodies on a circular orbit about their center of mass, at rest
+    #[test]
+    fn circular_binary() {
+        let config = GravityConfig { g: 1.0, softening: Softening::None, ..default() };
+        let (m1, m2) = (3.0, 1.0);
+        let m = m1 + m2;
+        let v = (config.g * m).sqrt();
+        let bodies = [
+            (m1, Vec3::new(-m2 / m, 0.0, 0.0), Vec3::new(0.0, -v * m2 / m, 0.0)),
+            (m2, Vec3::new(m1 / m, 0.0, 0.0), Vec3::new(0.0, v * m1 / m, 0.0)),
+        ];
+
+        let c = Conservation::measure(&bodies, &config);
+        let g = config.g as f64;
+        let (m1, m2) = (m1 as f64, m2 as f64);
+        assert!((c.potential_energy + g * m1 * m2).abs() < 1e-6);
+        assert!((c.total_energy() + 0.5 * g * m1 * m2).abs() < 1e-6);
+        assert!(c.momentum.length() < 1e-6);
+        assert!(c.center_of_mass.length() < 1e-6);
+        // L = mu sqrt(G M a), with a = 1
+        let mu = m1 * m2 / (m1 + m2);
+        assert!((c.angular_momentum.z - mu * (g * (m1 + m2)).sqrt()).abs() < 1e-6);
+    }
+
+    /// Half the sum of m phi over the bodies counts each pair once
+    #[test]
+    fn potentials_give_pair_energy() {
+        let config = GravityConfig { g: 1.0, softening: Softening::Plummer(0.1), ..default() };
+        let bodies = [
+            (1.0, Vec3::ZERO, Vec3::ZERO),
+            (2.0, Vec3::X, Vec3::ZERO),
+            (3.0, Vec3::Y * 2.0, Vec3::ZERO),
+        ];
+        let potentials: Vec<f32> = bodies.iter()
+            .map(|(_,p,_)| bodies.iter()
+                .filter(|(_,q,_)| q != p)
+                .map(|(m,q,_)| m * config.pair_potential(p.distance_squared(*q)))
+                .sum())
+            .collect();
+
+        let exact = Conservation::measure(&bodies, &config);
+        let from_potentials = Conservation::with_potentials(&bodies, &potentials);
+        assert!((exact.potential_energy - from_potentials.potential_energy).abs() < 1e-5);
+    }
+}
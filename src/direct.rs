@@ -0,0 +1,163 @@
+use bevy::prelude::*;
+use rayon::prelude::*;
+
+use crate::bhtree::NBody;
+use crate::gravity::GravityConfig;
+
+// Direct O(N^2) summation
+//
+// Exact up to floating point, so it serves as the reference the tree and
+// multipole solvers are validated against.  Each pair is evaluated once and
+// applied to both bodies (Newton's third law), with rows of the pair matrix
+// distributed across threads.  Every thread accumulates into its own buffer,
+// and the buffers are summed at the end.
+
+/// Per-thread accumulator: accelerations and potentials of every body, plus
+/// overlapping pairs
+type Partial = (Vec<Vec3>, Vec<f32>, Vec<(usize,usize)>);
+
+/// Per-thread accumulator of accelerations, jerks and potentials, plus
+/// overlapping pairs
+type JerkPartial = (Vec<Vec3>, Vec<Vec3>, Vec<f32>, Vec<(usize,usize)>);
+
+/// Compute the acceleration of every body, and its potential when
+/// `config.potential` is set, and report bodies that overlap
+pub fn collect_accelerations(bodies: &[NBody], config: &GravityConfig) -> Vec<(Entity,Vec3,f32,Vec<Entity>)> {
+    let n = bodies.len();
+    let empty = || (vec![Vec3::ZERO; n], vec![0.0; n], Vec::new());
+
+    let (accels, potentials, overlaps): Partial = (0..n).into_par_iter()
+        .fold(empty, |(mut accels, mut potentials, mut overlaps), i| {
+            let body = &bodies[i];
+            for (j, other) in bodies.iter().enumerate().skip(i + 1) {
+                let diff = other.position - body.position;
+                let dist2 = diff.length_squared();
+                if dist2 > 0.0 {
+                    let f = diff * config.pair_factor(dist2);
+                    accels[i] += f * other.mass;
+                    accels[j] -= f * body.mass;
+                    if config.potential {
+                        let phi = config.pair_potential(dist2);
+                        potentials[i] += phi * other.mass;
+                        potentials[j] += phi * body.mass;
+                    }
+                }
+                let radii = body.radius + other.radius;
+                if dist2 < radii * radii {
+                    overlaps.push((i, j));
+                }
+            }
+            (accels, potentials, overlaps)
+        })
+        .reduce(empty, |(mut accels, mut potentials, mut overlaps), (other_accels, other_potentials, mut other_overlaps)| {
+            for (a, b) in accels.iter_mut().zip(other_accels) {
+                *a += b;
+            }
+            for (p, q) in potentials.iter_mut().zip(other_potentials) {
+                *p += q;
+            }
+            overlaps.append(&mut other_overlaps);
+            (accels, potentials, overlaps)
+        });
+
+    let collisions = overlapping(bodies, overlaps);
+    bodies.iter()
+        .zip(accels)
+        .zip(potentials)
+        .zip(collisions)
+        .map(|(((body, accel), potential), collided_with)| (body.entity, accel, potential, collided_with))
+        .collect()
+}
+
+/// Bodies each body overlaps, from the list of overlapping pairs
+fn overlapping(bodies: &[NBody], overlaps: Vec<(usize,usize)>) -> Vec<Vec<Entity>> {
+    let mut collisions = vec![Vec::new(); bodies.len()];
+    for (i, j) in overlaps {
+        collisions[i].push(bodies[j].entity);
+        collisions[j].push(bodies[i].entity);
+    }
+    collisions
+}
+
+/// Compute the acceleration, potential and overlapping bodies of only the
+/// bodies at the given indices, in the same order, due to all bodies
+pub fn accelerations_of(bodies: &[NBody], active: &[usize], config: &GravityConfig) -> Vec<(Vec3,f32,Vec<Entity>)> {
+    active.par_iter()
+        .map(|&i| {
+            let body = &bodies[i];
+            let mut accel = Vec3::ZERO;
+            let mut potential = 0.0;
+            let mut collided_with = Vec::new();
+            for (j, other) in bodies.iter().enumerate() {
+                let diff = other.position - body.position;
+                let dist2 = diff.length_squared();
+                if dist2 > 0.0 {
+                    accel += diff * (other.mass * config.pair_factor(dist2));
+                    if config.potential {
+                        potential += other.mass * config.pair_potential(dist2);
+                    }
+                }
+                let radii = body.radius + other.radius;
+                if dist2 < radii * radii && j != i {
+                    collided_with.push(other.entity);
+                }
+            }
+            (accel, potential, collided_with)
+        })
+        .collect()
+}
+
+/// Compute the acceleration and jerk (time derivative of the acceleration) of
+/// every body moving at the given velocities, its potential when
+/// `config.potential` is set, and the bodies it overlaps, in the same order as
+/// `bodies`
+#[allow(clippy::type_complexity)]
+pub fn accelerations_and_jerks(bodies: &[NBody], velocities: &[Vec3], config: &GravityConfig) -> (Vec<Vec3>, Vec<Vec3>, Vec<f32>, Vec<Vec<Entity>>) {
+    let n = bodies.len();
+    let empty = || (vec![Vec3::ZERO; n], vec![Vec3::ZERO; n], vec![0.0; n], Vec::new());
+
+    let (accels, jerks, potentials, overlaps) = (0..n).into_par_iter()
+        .fold(empty, |(mut accels, mut jerks, mut potentials, mut overlaps): JerkPartial, i| {
+            let body = &bodies[i];
+            for (j, other) in bodies.iter().enumerate().skip(i + 1) {
+                let diff = other.position - body.position;
+                let dist2 = diff.length_squared();
+                if dist2 > 0.0 {
+                    let dv = velocities[j] - velocities[i];
+                    let f = config.pair_factor(dist2);
+                    let k = config.jerk_factor(dist2) * diff.dot(dv);
+                    let a = diff * f;
+                    let jerk = dv * f + diff * k;
+                    accels[i] += a * other.mass;
+                    accels[j] -= a * body.mass;
+                    jerks[i] += jerk * other.mass;
+                    jerks[j] -= jerk * body.mass;
+                    if config.potential {
+                        let phi = config.pair_potential(dist2);
+                        potentials[i] += phi * other.mass;
+                        potentials[j] += phi * body.mass;
+                    }
+                }
+                let radii = body.radius + other.radius;
+                if dist2 < radii * radii {
+                    overlaps.push((i, j));
+                }
+            }
+            (accels, jerks, potentials, overlaps)
+        })
+        .reduce(empty, |(mut accels, mut jerks, mut potentials, mut overlaps), (other_accels, other_jerks, other_potentials, mut other_overlaps)| {
+            for (a, b) in accels.iter_mut().zip(other_accels) {
+                *a += b;
+            }
+            for (a, b) in jerks.iter_mut().zip(other_jerks) {
+                *a += b;
+            }
+            for (p, q) in potentials.iter_mut().zip(other_potentials) {
+                *p += q;
+            }
+            overlaps.append(&mut other_overlaps);
+            (accels, jerks, potentials, overlaps)
+        });
+
+    (accels, jerks, potentials, overlapping(bodies, overlaps))
+}
@@ -0,0 +1,376 @@
+use bevy::prelude::*;
+use rayon::prelude::*;
+
+use crate::bhtree::{BBox3, NBody};
+use crate::gravity::{self, GravityConfig, MultipoleOrder};
+
+// Fast Multipole Method solver
+//
+// Builds an octree using the same BBox3 subdivision as the Barnes-Hut tree,
+// then finds all interactions with a single dual-tree traversal (Dehnen 2002):
+// well separated cell pairs interact through multipole-to-local (M2L)
+// translations, nearby leaf pairs through direct particle-particle (P2P)
+// summation.  Local expansions are then passed down the tree (L2L) and
+// evaluated at each body (L2P), so the total cost is O(N).
+
+/// Stop subdividing past this depth, so coincident bodies end up sharing a leaf
+const MAX_DEPTH: usize = 32;
+
+struct Cell {
+    bounds: BBox3,
+    first_body: usize,
+    num_bodies: usize,
+    first_child: usize,
+    num_children: usize,
+    mass: f32,
+    center_of_mass: Vec3,
+    quadrupole: Mat3,
+    /// Distance from the center of mass to the furthest body in the cell
+    radius: f32,
+}
+
+impl Cell {
+    fn new(bounds:BBox3, first_body:usize, num_bodies:usize) -> Self {
+        Cell {
+            bounds,
+            first_body,
+            num_bodies,
+            first_child: 0,
+            num_children: 0,
+            mass: 0.0,
+            center_of_mass: bounds.center(),
+            quadrupole: Mat3::ZERO,
+            radius: 0.0,
+        }
+    }
+
+    fn is_leaf(&self) -> bool {
+        self.num_children == 0
+    }
+
+    fn bodies(&self) -> std::ops::Range<usize> {
+        self.first_body..self.first_body + self.num_bodies
+    }
+
+    fn children(&self) -> std::ops::Range<usize> {
+        self.first_child..self.first_child + self.num_children
+    }
+}
+
+/// Second order local expansion of the far field about a cell's center of
+/// mass: the field, its gradient, and its second derivatives, where
+/// `hessian[i]` holds the second derivatives of field component i.  The
+/// potential, whose negative gradient is the field, is carried to third order.
+#[derive(Clone,Copy)]
+struct Local {
+    potential: f32,
+    field: Vec3,
+    gradient: Mat3,
+    hessian: [Mat3; 3],
+}
+
+impl Local {
+    const ZERO: Local = Local { potential: 0.0, field: Vec3::ZERO, gradient: Mat3::ZERO, hessian: [Mat3::ZERO; 3] };
+
+    /// Contract the second derivatives with `d` once, giving a matrix
+    fn hessian_dot(&self, d: Vec3) -> Mat3 {
+        Mat3::from_cols(self.hessian[0] * d, self.hessian[1] * d, self.hessian[2] * d).transpose()
+    }
+
+    /// Potential at offset `d` from the expansion center
+    fn potential_at(&self, d: Vec3, hd: Mat3) -> f32 {
+        self.potential - d.dot(self.field + 0.5 * (self.gradient * d) + (hd * d) / 6.0)
+    }
+
+    /// Shift the expansion center by `d` (L2L)
+    fn translate(&self, d: Vec3) -> Local {
+        let hd = self.hessian_dot(d);
+        Local {
+            potential: self.potential_at(d, hd),
+            field: self.field + self.gradient * d + 0.5 * (hd * d),
+            gradient: self.gradient + hd,
+            hessian: self.hessian,
+        }
+    }
+
+    /// Evaluate the field and potential at offset `d` from the expansion
+    /// center (L2P)
+    fn evaluate(&self, d: Vec3) -> (Vec3, f32) {
+        let hd = self.hessian_dot(d);
+        (self.field + self.gradient * d + 0.5 * (hd * d), self.potential_at(d, hd))
+    }
+}
+
+impl std::ops::Add for Local {
+    type Output = Local;
+    fn add(self, other: Local) -> Local {
+        Local {
+            potential: self.potential + other.potential,
+            field: self.field + other.field,
+            gradient: self.gradient + other.gradient,
+            hessian: [
+                self.hessian[0] + other.hessian[0],
+                self.hessian[1] + other.hessian[1],
+                self.hessian[2] + other.hessian[2],
+            ],
+        }
+    }
+}
+
+pub struct FmmTree {
+    cells: Vec<Cell>,
+    bodies: Vec<NBody>,
+}
+
+impl FmmTree {
+
+    /// Build the tree, with at most `bucket_size` bodies per leaf, and compute
+    /// the multipole moments of every cell
+    pub fn from<I>(bodies:I, bucket_size:usize) -> Self
+    where I:Iterator<Item=NBody>
+    {
+        let mut bodies: Vec<NBody> = bodies.collect();
+        let bounds = BBox3::from(bodies.iter().map(|b| &b.position));
+        let mut cells = vec![Cell::new(bounds, 0, bodies.len())];
+
+        FmmTree::split(&mut cells, &mut bodies, 0, 0, bucket_size.max(1));
+
+        let mut tree = FmmTree { cells, bodies };
+        tree.upward(0);
+        tree
+    }
+
+    /// Recursively partition the bodies of a cell into its octants.  Children
+    /// are allocated contiguously, and always after their parent.
+    fn split(cells: &mut Vec<Cell>, bodies: &mut [NBody], index: usize, depth: usize, bucket_size: usize) {
+        let cell = &cells[index];
+        if cell.num_bodies <= bucket_size || depth >= MAX_DEPTH {
+            return;
+        }
+
+        let bounds = cell.bounds;
+        let range = cell.bodies();
+        bodies[range.clone()].sort_unstable_by_key(|b| bounds.quadrant_index_for(&b.position));
+
+        let mut counts = [0usize; 8];
+        for body in &bodies[range.clone()] {
+            counts[bounds.quadrant_index_for(&body.position)] += 1;
+        }
+
+        let first_child = cells.len();
+        let mut first_body = range.start;
+        for (subbounds, count) in bounds.subdivide().iter().zip(counts) {
+            if count > 0 {
+                cells.push(Cell::new(*subbounds, first_body, count));
+                first_body += count;
+            }
+        }
+        cells[index].first_child = first_child;
+        cells[index].num_children = cells.len() - first_child;
+
+        for child in first_child..cells.len() {
+            FmmTree::split(cells, bodies, child, depth + 1, bucket_size);
+        }
+    }
+
+    /// Compute mass, center of mass, quadrupole and radius bottom-up (P2M, M2M)
+    fn upward(&mut self, index: usize) {
+        let (mass, center_of_mass, quadrupole, radius);
+
+        if self.cells[index].is_leaf() {
+            let bodies = &self.bodies[self.cells[index].bodies()];
+            (mass, center_of_mass) = gravity::mass_and_center_of_mass(
+                bodies.iter().map(|b| (b.position, b.mass)), self.cells[index].bounds.center());
+            quadrupole = bodies.iter()
+                .fold(Mat3::ZERO, |acc,b| acc + gravity::point_quadrupole(b.mass, b.position - center_of_mass));
+            radius = bodies.iter()
+                .map(|b| b.position.distance(center_of_mass))
+                .fold(0.0, f32::max);
+        } else {
+            for child in self.cells[index].children() {
+                self.upward(child);
+            }
+            let children = &self.cells[self.cells[index].children()];
+            mass = children.iter().map(|c| c.mass).sum::<f32>();
+            center_of_mass = if mass > 0.0 {
+                children.iter().map(|c| c.center_of_mass * c.mass).sum::<Vec3>() / mass
+            } else {
+                self.cells[index].bounds.center()
+            };
+            quadrupole = children.iter()
+                .fold(Mat3::ZERO, |acc,c| acc + c.quadrupole + gravity::point_quadrupole(c.mass, c.center_of_mass - center_of_mass));
+            radius = children.iter()
+                .map(|c| c.center_of_mass.distance(center_of_mass) + c.radius)
+                .fold(0.0, f32::max);
+        }
+
+        let cell = &mut self.cells[index];
+        cell.mass = mass;
+        cell.center_of_mass = center_of_mass;
+        cell.quadrupole = quadrupole;
+        cell.radius = radius;
+    }
+
+    /// Are two cells far enough apart to interact through their expansions?
+    fn well_separated(&self, a: usize, b: usize, config: &GravityConfig) -> bool {
+        let (a, b) = (&self.cells[a], &self.cells[b]);
+        a.radius + b.radius < config.theta * a.center_of_mass.distance(b.center_of_mass)
+    }
+
+    /// Dual-tree traversal of the interactions of a cell with itself
+    fn traverse_self(&self, a: usize, config: &GravityConfig, m2l: &mut Vec<(usize,usize)>, p2p: &mut Vec<(usize,usize)>) {
+        let cell = &self.cells[a];
+        if cell.is_leaf() {
+            p2p.push((a,a));
+            return;
+        }
+        for i in cell.children() {
+            self.traverse_self(i, config, m2l, p2p);
+            for j in i+1..cell.children().end {
+                self.traverse(i, j, config, m2l, p2p);
+            }
+        }
+    }
+
+    /// Dual-tree traversal of the mutual interactions between two distinct
+    /// cells.  Every interaction is recorded once per direction.
+    fn traverse(&self, a: usize, b: usize, config: &GravityConfig, m2l: &mut Vec<(usize,usize)>, p2p: &mut Vec<(usize,usize)>) {
+        if self.well_separated(a, b, config) {
+            m2l.push((a,b));
+            m2l.push((b,a));
+            return;
+        }
+
+        let (ca, cb) = (&self.cells[a], &self.cells[b]);
+        if ca.is_leaf() && cb.is_leaf() {
+            p2p.push((a,b));
+            p2p.push((b,a));
+        } else if cb.is_leaf() || (!ca.is_leaf() && ca.radius >= cb.radius) {
+            for child in ca.children() {
+                self.traverse(child, b, config, m2l, p2p);
+            }
+        } else {
+            for child in cb.children() {
+                self.traverse(a, child, config, m2l, p2p);
+            }
+        }
+    }
+
+    /// Local expansion about the center of mass of `target` due to the
+    /// multipole expansion of `source` (M2L)
+    fn multipole_to_local(&self, target: usize, source: usize, config: &GravityConfig) -> Local {
+        let (t, s) = (&self.cells[target], &self.cells[source]);
+
+        let r = t.center_of_mass - s.center_of_mass;
+        let r2 = config.softened_dist2(r.length_squared());
+        let inv_r = 1.0 / r2.sqrt();
+        let inv_r3 = inv_r / r2;
+        let gm = config.g * s.mass;
+
+        let mut field = config.pair_acceleration(-r, s.mass);
+        let mut potential = 0.0;
+        if config.potential {
+            potential = s.mass * config.pair_potential(r.length_squared());
+        }
+        if config.multipole_order == MultipoleOrder::Quadrupole {
+            field += gravity::quadrupole_acceleration(config.g, &s.quadrupole, r, r2);
+            if config.potential {
+                potential += gravity::quadrupole_potential(config.g, &s.quadrupole, r, r2);
+            }
+        }
+
+        let outer = Mat3::from_cols(r * r.x, r * r.y, r * r.z);
+        let gradient = (outer * (3.0 / r2) - Mat3::IDENTITY) * (gm * inv_r3);
+
+        // d2 a_i / dx_j dx_k = GM (3 (r_i d_jk + r_j d_ik + r_k d_ij) / r^5 - 15 r_i r_j r_k / r^7)
+        let inv_r5 = inv_r3 / r2;
+        let axes = [Vec3::X, Vec3::Y, Vec3::Z];
+        let hessian = [0, 1, 2].map(|i| {
+            let e = axes[i];
+            let ri = r[i];
+            let sym = Mat3::from_diagonal(Vec3::splat(ri))
+                + Mat3::from_cols(r * e.x, r * e.y, r * e.z)
+                + Mat3::from_cols(e * r.x, e * r.y, e * r.z);
+            (sym * 3.0 - outer * (15.0 * ri / r2)) * (gm * inv_r5)
+        });
+
+        Local { potential, field, gradient, hessian }
+    }
+
+    /// Group (target, source) pairs by target
+    fn by_target(pairs: &[(usize,usize)], num_cells: usize) -> Vec<Vec<usize>> {
+        let mut sources = vec![Vec::new(); num_cells];
+        for &(target, source) in pairs {
+            sources[target].push(source);
+        }
+        sources
+    }
+
+    /// Compute the acceleration of every body, and its potential if enabled in
+    /// the config, and report bodies that overlap
+    pub fn collect_accelerations(self, config: &GravityConfig) -> Vec<(Entity,Vec3,f32,Vec<Entity>)> {
+        if self.cells[0].num_bodies == 0 {
+            return Vec::new();
+        }
+
+        let mut m2l = Vec::new();
+        let mut p2p = Vec::new();
+        self.traverse_self(0, config, &mut m2l, &mut p2p);
+
+        // M2L, in parallel over target cells
+        let m2l_sources = FmmTree::by_target(&m2l, self.cells.len());
+        let mut locals: Vec<Local> = m2l_sources.par_iter()
+            .enumerate()
+            .map(|(target, sources)| {
+                sources.iter().fold(Local::ZERO, |acc, &source| acc + self.multipole_to_local(target, source, config))
+            })
+            .collect();
+
+        // L2L, top-down.  Children always come after their parent.
+        for index in 0..self.cells.len() {
+            let cell = &self.cells[index];
+            for child in cell.children() {
+                let d = self.cells[child].center_of_mass - cell.center_of_mass;
+                locals[child] = locals[child] + locals[index].translate(d);
+            }
+        }
+
+        // L2P and P2P, in parallel over leaves
+        let p2p_sources = FmmTree::by_target(&p2p, self.cells.len());
+        let (tree, locals, p2p_sources) = (&self, &locals, &p2p_sources);
+        self.cells.par_iter()
+            .enumerate()
+            .filter(|(_, cell)| cell.is_leaf())
+            .flat_map_iter(|(target, cell)| {
+                let local = locals[target];
+                let sources = &p2p_sources[target];
+                cell.bodies().map(move |i| {
+                    let body = &tree.bodies[i];
+                    let (mut accel, mut potential) = local.evaluate(body.position - cell.center_of_mass);
+                    let mut collided_with = Vec::new();
+
+                    for &source in sources {
+                        for j in tree.cells[source].bodies() {
+                            if i == j {
+                                continue;
+                            }
+                            let other = &tree.bodies[j];
+                            let diff = other.position - body.position;
+                            let dist2 = diff.length_squared();
+                            let radii = body.radius + other.radius;
+                            accel += config.pair_acceleration(diff, other.mass);
+                            if config.potential && dist2 > 0.0 {
+                                potential += other.mass * config.pair_potential(dist2);
+                            }
+                            if dist2 < radii * radii {
+                                collided_with.push(other.entity);
+                            }
+                        }
+                    }
+
+                    (body.entity, accel, potential, collided_with)
+                })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,225 @@
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use rayon::prelude::*;
+
+use crate::bhtree::{BBox3, BHTreeNode, NBody};
+use crate::direct;
+use crate::fmm::FmmTree;
+use crate::gravity::{GravityConfig, Solver};
+use crate::linear_octree::LinearOctree;
+
+/// Source of the accelerations the integrators advance bodies with
+pub trait Forces {
+    /// Acceleration of every body, in the same order as `bodies`
+    fn accelerations(&mut self, bodies: &[NBody]) -> Vec<Vec3>;
+
+    /// Acceleration of only the bodies at the given indices, in the same order
+    /// as `active`.  By default every body is evaluated, and the rest dropped.
+    fn accelerations_of(&mut self, bodies: &[NBody], active: &[usize]) -> Vec<Vec3> {
+        let accelerations = self.accelerations(bodies);
+        active.iter().map(|&i| accelerations[i]).collect()
+    }
+
+    /// Acceleration and jerk of every body moving at `velocities`, in the
+    /// same order as `bodies`
+    fn accelerations_and_jerks(&mut self, bodies: &[NBody], velocities: &[Vec3]) -> (Vec<Vec3>, Vec<Vec3>);
+}
+
+/// Accelerations from outside the simulated bodies, such as a uniform field or
+/// a fixed background potential, as functions of position.  Every evaluation
+/// of `Gravity` adds them to the self-gravity, so they are integrated by the
+/// selected scheme like gravity itself.  Hermite's jerks leave them out, and
+/// they carry no potential, so the energy diagnostics do not count them.
+#[derive(Resource,Default)]
+pub struct ExternalFields(pub Vec<Box<dyn Fn(Vec3) -> Vec3 + Send + Sync>>);
+
+impl ExternalFields {
+    pub fn add(&mut self, field: impl Fn(Vec3) -> Vec3 + Send + Sync + 'static) -> &mut Self {
+        self.0.push(Box::new(field));
+        self
+    }
+
+    /// Total acceleration of the fields at `position`
+    pub fn acceleration(&self, position: Vec3) -> Vec3 {
+        self.0.iter().map(|field| field(position)).sum()
+    }
+}
+
+/// Self-gravity of the bodies, using the solver selected in `config`, plus
+/// any external fields
+pub struct Gravity<'a> {
+    pub config: &'a GravityConfig,
+    /// Buffers reused by the linear octree solver between evaluations
+    pub octree: &'a mut LinearOctree,
+    /// Potential at every body, in the same order as `bodies`, from the last
+    /// evaluation that covered all of them.  Only filled in when
+    /// `config.potential` is set.
+    pub potentials: Vec<f32>,
+    /// Bodies each body overlaps, in the same order as `bodies`, from the last
+    /// evaluation that covered all of them
+    pub collisions: Vec<Vec<Entity>>,
+    /// Fields added to every acceleration, if any
+    pub fields: Option<&'a ExternalFields>,
+}
+
+impl<'a> Gravity<'a> {
+    pub fn new(config: &'a GravityConfig, octree: &'a mut LinearOctree) -> Self {
+        Gravity { config, octree, potentials: Vec::new(), collisions: Vec::new(), fields: None }
+    }
+
+    /// Add the accelerations of `fields` to every evaluation
+    pub fn with_fields(self, fields: &'a ExternalFields) -> Self {
+        Gravity { fields: Some(fields), ..self }
+    }
+
+    /// Add the external fields to the accelerations of the bodies at the
+    /// given indices, in the same order as `active`
+    fn add_fields(&self, bodies: &[NBody], active: &[usize], accels: &mut [Vec3]) {
+        let Some(fields) = self.fields.filter(|f| !f.0.is_empty()) else {
+            return;
+        };
+        accels.par_iter_mut()
+            .zip(active.par_iter())
+            .for_each(|(accel, &i)| *accel += fields.acceleration(bodies[i].position));
+    }
+
+    /// Keep the potentials and overlaps of an evaluation of every body
+    fn keep(&mut self, potentials: Vec<f32>, collisions: Vec<Vec<Entity>>) {
+        if self.config.potential {
+            self.potentials = potentials;
+        }
+        self.collisions = collisions;
+    }
+}
+
+impl<'a> Gravity<'a> {
+    /// Self-gravity of every body, in the same order as `bodies`
+    fn self_gravity(&mut self, bodies: &[NBody]) -> Vec<Vec3> {
+        let config = self.config;
+        let results: Vec<(Entity,Vec3,f32,Vec<Entity>)> = match config.solver {
+            Solver::BarnesHut => {
+                let bounds = BBox3::from(bodies.iter().map(|b| &b.position));
+                BHTreeNode::from_bodies(&bounds, bodies.to_vec(), config)
+                    .collect_accelerations(config)
+            },
+            Solver::LinearBarnesHut => {
+                let octree = &mut *self.octree;
+                octree.clear();
+                for body in bodies {
+                    octree.push(body.entity, body.position, body.mass, body.radius);
+                }
+                octree.build(config.bucket_size);
+                octree.compute_accelerations(config);
+                octree.accelerations().map(|(e,a,p,c)| (e,a,p,c.to_vec())).collect()
+            },
+            Solver::Fmm => {
+                FmmTree::from(bodies.iter().copied(), config.bucket_size)
+                    .collect_accelerations(config)
+            },
+            Solver::Direct => {
+                // Already in order
+                let mut accels = Vec::with_capacity(bodies.len());
+                let mut potentials = Vec::with_capacity(bodies.len());
+                let mut collisions = Vec::with_capacity(bodies.len());
+                for (_, accel, potential, collided_with) in direct::collect_accelerations(bodies, config) {
+                    accels.push(accel);
+                    potentials.push(potential);
+                    collisions.push(collided_with);
+                }
+                self.keep(potentials, collisions);
+                return accels;
+            },
+        };
+
+        // The tree solvers return bodies in tree order
+        let order: HashMap<Entity,usize> = bodies.iter()
+            .enumerate()
+            .map(|(i,b)| (b.entity, i))
+            .collect();
+        let mut accels = vec![Vec3::ZERO; bodies.len()];
+        let mut potentials = vec![0.0; bodies.len()];
+        let mut collisions = vec![Vec::new(); bodies.len()];
+        for (entity, accel, potential, collided_with) in results {
+            let i = order[&entity];
+            accels[i] = accel;
+            potentials[i] = potential;
+            collisions[i] = collided_with;
+        }
+        self.keep(potentials, collisions);
+        accels
+    }
+
+    /// Self-gravity of the bodies at the given indices.  The tree is built
+    /// from every body, but only walked for the active ones.  The FMM's cost
+    /// is dominated by the traversal shared by all bodies, so it still
+    /// evaluates every body.
+    fn self_gravity_of(&mut self, bodies: &[NBody], active: &[usize]) -> Vec<Vec3> {
+        let config = self.config;
+        let results: Vec<(Vec3,f32,Vec<Entity>)> = match config.solver {
+            Solver::BarnesHut => {
+                let bounds = BBox3::from(bodies.iter().map(|b| &b.position));
+                let tree = BHTreeNode::from_bodies(&bounds, bodies.to_vec(), config);
+                active.par_iter()
+                    .map(|&i| tree.calculate_acceleration(&bodies[i], config))
+                    .collect()
+            },
+            Solver::LinearBarnesHut => {
+                let octree = &mut *self.octree;
+                octree.clear();
+                for body in bodies {
+                    octree.push(body.entity, body.position, body.mass, body.radius);
+                }
+                octree.build(config.bucket_size);
+                octree.accelerations_of(active, config)
+            },
+            Solver::Fmm => {
+                let accelerations = self.self_gravity(bodies);
+                return active.iter().map(|&i| accelerations[i]).collect();
+            },
+            Solver::Direct => direct::accelerations_of(bodies, active, config),
+        };
+
+        if active.len() != bodies.len() {
+            return results.into_iter().map(|(accel,_,_)| accel).collect();
+        }
+
+        let mut accels = Vec::with_capacity(bodies.len());
+        let mut potentials = vec![0.0; bodies.len()];
+        let mut collisions = vec![Vec::new(); bodies.len()];
+        for (&i, (accel, potential, collided_with)) in active.iter().zip(results) {
+            accels.push(accel);
+            potentials[i] = potential;
+            collisions[i] = collided_with;
+        }
+        self.keep(potentials, collisions);
+        accels
+    }
+
+}
+
+impl<'a> Forces for Gravity<'a> {
+    fn accelerations(&mut self, bodies: &[NBody]) -> Vec<Vec3> {
+        let mut accels = self.self_gravity(bodies);
+        let all: Vec<usize> = (0..bodies.len()).collect();
+        self.add_fields(bodies, &all, &mut accels);
+        accels
+    }
+
+    fn accelerations_of(&mut self, bodies: &[NBody], active: &[usize]) -> Vec<Vec3> {
+        let mut accels = self.self_gravity_of(bodies, active);
+        self.add_fields(bodies, active, &mut accels);
+        accels
+    }
+
+    /// The tree solvers carry no velocity moments, so jerks always come from
+    /// direct summation, along with matching accelerations, whatever
+    /// `config.solver` is.  That is O(N^2), and the plugin warns when Hermite
+    /// is selected with another solver.
+    fn accelerations_and_jerks(&mut self, bodies: &[NBody], velocities: &[Vec3]) -> (Vec<Vec3>, Vec<Vec3>) {
+        let (mut accels, jerks, potentials, collisions) = direct::accelerations_and_jerks(bodies, velocities, self.config);
+        self.keep(potentials, collisions);
+        let all: Vec<usize> = (0..bodies.len()).collect();
+        self.add_fields(bodies, &all, &mut accels);
+        (accels, jerks)
+    }
+}
@@ -0,0 +1,292 @@
+use std::str::FromStr;
+
+use bevy::prelude::*;
+
+use crate::units::{self, UnitSystem};
+
+/// Plummer softening length of the default configuration, m
+const SOFTENING: f64 = units::AU / 400.0;
+
+/// Rule used to decide whether a tree node must be opened, or whether it is
+/// far enough from a body to be approximated by its center of mass.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum OpeningCriterion {
+    /// Classic Barnes-Hut: open the node when size / distance >= theta
+    BarnesHut,
+    /// Offset criterion: open the node when the distance is less than
+    /// size / theta + delta, where delta is the offset of the center of mass
+    /// from the geometric center of the node.  This guards against lopsided
+    /// nodes being accepted too early.
+    Offset,
+}
+
+/// Number of terms kept in the far-field expansion of a tree node
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum MultipoleOrder {
+    /// Total mass at the center of mass only
+    Monopole,
+    /// Monopole plus the traceless quadrupole tensor
+    Quadrupole,
+}
+
+/// Algorithm used to compute gravitational accelerations
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Solver {
+    /// O(N log N) Barnes-Hut tree walk per body, on the boxed recursive tree
+    BarnesHut,
+    /// Barnes-Hut on a flat, Morton ordered octree
+    LinearBarnesHut,
+    /// O(N) Fast Multipole Method with dual-tree traversal
+    Fmm,
+    /// Exact O(N^2) pairwise summation, as a reference
+    Direct,
+}
+
+/// Softening kernel, which limits the force between close bodies
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Softening {
+    /// Pure Newtonian 1/r^2 force
+    None,
+    /// Plummer sphere with the given epsilon: 1/(r^2 + eps^2)
+    Plummer(f32),
+    /// Monaghan cubic spline with the given Plummer-equivalent epsilon, as
+    /// used in GADGET.  Exactly Newtonian beyond h = 2.8 eps.
+    Spline(f32),
+}
+
+/// Tunables for the gravity solvers
+#[derive(Resource,Clone,Copy,Debug)]
+pub struct GravityConfig {
+    /// Opening angle.  Smaller is more accurate, larger is faster.
+    pub theta: f32,
+    /// Softening kernel, applied to every pairwise and far-field interaction
+    pub softening: Softening,
+    /// Gravitational constant
+    pub g: f32,
+    /// Rule used to accept or open tree nodes
+    pub opening_criterion: OpeningCriterion,
+    /// Far-field expansion used for accepted nodes
+    pub multipole_order: MultipoleOrder,
+    /// Algorithm used to compute accelerations
+    pub solver: Solver,
+    /// Number of bodies a tree leaf holds before it is split.  Bodies within
+    /// a leaf interact by direct summation.  On the 10k body disk the linear
+    /// octree is fastest at 8 to 16, the pointer tree at 16 to 32 and the FMM
+    /// at 16 (timings in benches/bucket_size.rs).
+    pub bucket_size: usize,
+    /// Also compute the gravitational potential at each body, in the same
+    /// walk as its acceleration
+    pub potential: bool,
+}
+
+impl GravityConfig {
+
+    /// The default configuration for a simulation in `units`, with G and the
+    /// softening length converted into them
+    pub fn for_units(units: &UnitSystem) -> Self {
+        GravityConfig {
+            theta: 0.5,
+            softening: Softening::Plummer(units.length(SOFTENING)),
+            g: units.g(),
+            opening_criterion: OpeningCriterion::BarnesHut,
+            multipole_order: MultipoleOrder::Quadrupole,
+            solver: Solver::LinearBarnesHut,
+            bucket_size: 8,
+            potential: false,
+        }
+    }
+
+    /// Should a node of the given size, center and center of mass be opened
+    /// when computing the acceleration of a body at `p`?
+    pub fn should_open(&self, size: f32, center: Vec3, center_of_mass: Vec3, p: Vec3) -> bool {
+        let dist = center_of_mass.distance(p);
+        match self.opening_criterion {
+            OpeningCriterion::BarnesHut => size / dist >= self.theta,
+            OpeningCriterion::Offset => {
+                let offset = center_of_mass.distance(center);
+                dist < size / self.theta + offset
+            }
+        }
+    }
+
+    /// Length scale of the softening kernel, or zero without softening
+    pub fn softening_length(&self) -> f32 {
+        match self.softening {
+            Softening::None => 0.0,
+            Softening::Plummer(eps) | Softening::Spline(eps) => eps,
+        }
+    }
+
+    /// Squared separation used for the quadrupole and field gradient terms.
+    /// Only the Plummer kernel modifies these; the spline is Newtonian at the
+    /// distances where nodes are accepted.
+    pub fn softened_dist2(&self, dist2: f32) -> f32 {
+        match self.softening {
+            Softening::Plummer(eps) => dist2 + eps * eps,
+            Softening::None | Softening::Spline(_) => dist2,
+        }
+    }
+
+    /// Acceleration on a body due to a point mass at offset `diff`
+    pub fn pair_acceleration(&self, diff: Vec3, mass: f32) -> Vec3 {
+        let dist2 = diff.length_squared();
+        if dist2 > 0.0 {
+            diff * (mass * self.pair_factor(dist2))
+        } else {
+            Vec3::ZERO
+        }
+    }
+
+    /// Scale factor f such that the acceleration due to a unit mass at offset
+    /// d, with |d|^2 = dist2 > 0, is f * d
+    #[inline]
+    pub fn pair_factor(&self, dist2: f32) -> f32 {
+        match self.softening {
+            Softening::None => {
+                self.g / (dist2 * dist2.sqrt())
+            },
+            Softening::Plummer(eps) => {
+                let r2 = dist2 + eps * eps;
+                self.g / (r2 * r2.sqrt())
+            },
+            Softening::Spline(eps) => {
+                let h = 2.8 * eps;
+                let r = dist2.sqrt();
+                if r >= h {
+                    return self.g / (dist2 * r);
+                }
+                let h_inv = 1.0 / h;
+                let h_inv3 = h_inv * h_inv * h_inv;
+                let u = r * h_inv;
+                let w = if u < 0.5 {
+                    10.666_667 + u * u * (32.0 * u - 38.4)
+                } else {
+                    21.333_334 - 48.0 * u + 38.4 * u * u - 10.666_667 * u * u * u - 0.066_666_67 / (u * u * u)
+                };
+                self.g * h_inv3 * w
+            },
+        }
+    }
+
+    /// Potential energy per unit mass of a body due to a unit mass at
+    /// squared distance dist2 > 0, consistent with pair_factor()
+    pub fn pair_potential(&self, dist2: f32) -> f32 {
+        match self.softening {
+            Softening::None => {
+                -self.g / dist2.sqrt()
+            },
+            Softening::Plummer(eps) => {
+                -self.g / (dist2 + eps * eps).sqrt()
+            },
+            Softening::Spline(eps) => {
+                let h = 2.8 * eps;
+                let r = dist2.sqrt();
+                if r >= h {
+                    return -self.g / r;
+                }
+                let u = r / h;
+                let w = if u < 0.5 {
+                    -2.8 + u * u * (5.333_333 + u * u * (6.4 * u - 9.6))
+                } else {
+                    -3.2 + 0.066_666_67 / u + u * u * (10.666_667 + u * (-16.0 + u * (9.6 - 2.133_333_3 * u)))
+                };
+                self.g / h * w
+            },
+        }
+    }
+
+    /// Scale factor k such that the jerk due to a unit mass at offset d,
+    /// moving at relative velocity v, is f * v + k * (d . v) * d, where f is
+    /// the pair_factor().  That is, k = 2 df/d(r^2).
+    #[inline]
+    pub fn jerk_factor(&self, dist2: f32) -> f32 {
+        match self.softening {
+            Softening::None => {
+                -3.0 * self.g / (dist2 * dist2 * dist2.sqrt())
+            },
+            Softening::Plummer(eps) => {
+                let r2 = dist2 + eps * eps;
+                -3.0 * self.g / (r2 * r2 * r2.sqrt())
+            },
+            Softening::Spline(eps) => {
+                let h = 2.8 * eps;
+                let r = dist2.sqrt();
+                if r >= h {
+                    return -3.0 * self.g / (dist2 * dist2 * r);
+                }
+                let h_inv = 1.0 / h;
+                let h_inv5 = h_inv * h_inv * h_inv * h_inv * h_inv;
+                let u = r * h_inv;
+                // dw/du / u, for the kernel w of pair_factor()
+                let dw = if u < 0.5 {
+                    96.0 * u - 76.8
+                } else {
+                    let u2 = u * u;
+                    -48.0 / u + 76.8 - 32.0 * u + 0.2 / (u2 * u2 * u)
+                };
+                self.g * h_inv5 * dw
+            },
+        }
+    }
+}
+
+impl Default for GravityConfig {
+    fn default() -> Self {
+        GravityConfig::for_units(&UnitSystem::default())
+    }
+}
+
+impl FromStr for Solver {
+    type Err = String;
+
+    /// Parse a solver by name
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "barnes-hut" | "bh" => Ok(Solver::BarnesHut),
+            "linear" | "linear-barnes-hut" => Ok(Solver::LinearBarnesHut),
+            "fmm" => Ok(Solver::Fmm),
+            "direct" => Ok(Solver::Direct),
+            _ => Err(format!("unknown solver '{}', expected barnes-hut, linear, fmm or direct", s)),
+        }
+    }
+}
+
+/// Total mass and center of mass of point masses given as (position, mass).
+/// Moments are summed relative to the first point, so a clump far from the
+/// origin does not lose its extent to rounding; `fallback` is the center when
+/// there is no mass.
+pub fn mass_and_center_of_mass<I>(points: I, fallback: Vec3) -> (f32, Vec3)
+    where I: IntoIterator<Item=(Vec3,f32)>
+{
+    let mut points = points.into_iter().peekable();
+    let reference = points.peek().map_or(fallback, |(p,_)| *p);
+    let (mass, moment) = points
+        .fold((0.0, Vec3::ZERO), |(mass,moment),(p,m)| (mass + m, moment + (p - reference) * m));
+    let center_of_mass = if mass > 0.0 { reference + moment / mass } else { fallback };
+    (mass, center_of_mass)
+}
+
+/// Traceless quadrupole tensor of a point mass at offset `d` from the
+/// expansion center: m (3 d d^T - |d|^2 I)
+pub fn point_quadrupole(mass: f32, d: Vec3) -> Mat3 {
+    let outer = Mat3::from_cols(d * d.x, d * d.y, d * d.z);
+    (outer * 3.0 - Mat3::from_diagonal(Vec3::splat(d.length_squared()))) * mass
+}
+
+/// Potential due to the quadrupole term of a multipole expansion, with `r`
+/// and `r2` as for quadrupole_acceleration()
+pub fn quadrupole_potential(g: f32, quadrupole: &Mat3, r: Vec3, r2: f32) -> f32 {
+    let rqr = r.dot(*quadrupole * r);
+    -0.5 * g * rqr / (r2 * r2 * r2.sqrt())
+}
+
+/// Acceleration due to the quadrupole term of a multipole expansion, where
+/// `r` points from the expansion center to the body and `r2` is its
+/// (possibly softened) squared length.
+pub fn quadrupole_acceleration(g: f32, quadrupole: &Mat3, r: Vec3, r2: f32) -> Vec3 {
+    let qr = *quadrupole * r;
+    let rqr = r.dot(qr);
+    let inv_r2 = 1.0 / r2;
+    let inv_r5 = inv_r2 * inv_r2 / r2.sqrt();
+    g * inv_r5 * (qr - 2.5 * rqr * inv_r2 * r)
+}
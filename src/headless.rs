@@ -0,0 +1,212 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use bevy::app::AppExit;
+use bevy::prelude::*;
+
+use crate::components::*;
+use crate::diagnostics::{BodyQuery, Conservation};
+use crate::gravity::GravityConfig;
+use crate::timestep::{FixedTimestep, PhysicsSchedule};
+use crate::NBodySet;
+
+// Headless runs
+//
+// Without a window there is no frame time to pace the simulation by, so every
+// app update runs exactly one physics step, as fast as the machine allows.
+// Every `snapshot_interval` steps the state of each body is written to a CSV
+// file in the output directory, and the conserved totals are appended to
+// diagnostics.csv, until `steps` steps have run and the app exits.  If the
+// output cannot be written the error is logged and the process exits with a
+// failure status, rather than running on without it.
+
+/// Runs the physics schedule for a fixed number of steps, writing snapshots
+/// and diagnostics, then exits
+pub struct HeadlessPlugin {
+    pub steps: u64,
+    pub snapshot_interval: u64,
+    /// Directory the snapshots and diagnostics are written to
+    pub output: PathBuf,
+}
+
+/// Progress of the run
+#[derive(Resource)]
+struct HeadlessRun {
+    steps: u64,
+    snapshot_interval: u64,
+    output: PathBuf,
+    taken: u64,
+    /// Whether the last snapshot is written
+    done: bool,
+    initial: Option<Conservation>,
+    diagnostics: Option<BufWriter<File>>,
+}
+
+impl Plugin for HeadlessPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(HeadlessRun {
+                steps: self.steps,
+                snapshot_interval: self.snapshot_interval.max(1),
+                output: self.output.clone(),
+                taken: 0,
+                done: false,
+                initial: None,
+                diagnostics: None,
+            })
+            .add_startup_system(Self::setup_system.pipe(Self::exit_on_error))
+            .add_system(Self::snapshot_system.pipe(Self::exit_on_error).in_set(NBodySet::Diagnostics))
+            .add_system(Self::step_system.after(Self::snapshot_system));
+    }
+}
+
+impl HeadlessPlugin {
+
+    fn setup_system(mut run: ResMut<HeadlessRun>) -> io::Result<()> {
+        let path = run.output.join("diagnostics.csv");
+        let file = fs::create_dir_all(&run.output)
+            .and_then(|_| File::create(&path))
+            .and_then(|file| {
+                let mut file = BufWriter::new(file);
+                writeln!(file, "step,time,kinetic_energy,potential_energy,total_energy,relative_energy_error,momentum,angular_momentum,center_of_mass_drift")?;
+                Ok(file)
+            })
+            .map_err(at(&path))?;
+        run.diagnostics = Some(file);
+        info!("Running {} steps headless, writing to {}", run.steps, run.output.display());
+        Ok(())
+    }
+
+    /// Log a failure to write the output and exit with a failure status
+    fn exit_on_error(In(result): In<io::Result<()>>) {
+        if let Err(err) = result {
+            error!("Headless run failed: {}", err);
+            std::process::exit(1);
+        }
+    }
+
+    /// Write a snapshot and the diagnostics every `snapshot_interval` steps
+    /// and after the last, and exit once it is written
+    fn snapshot_system(
+        mut run: ResMut<HeadlessRun>,
+        timestep: Res<FixedTimestep>,
+        config: Res<GravityConfig>,
+        bodies: Query<(Entity, &Mass, &Radius, &Position, &Velocity)>,
+        totals: BodyQuery,
+        mut exit: EventWriter<AppExit>,
+    ) -> io::Result<()> {
+        if run.done {
+            return Ok(());
+        }
+        let finished = run.taken == run.steps;
+        if !run.taken.is_multiple_of(run.snapshot_interval) && !finished {
+            return Ok(());
+        }
+
+        let path = run.output.join(format!("snapshot_{:06}.csv", run.taken));
+        write_snapshot(&path, &bodies).map_err(at(&path))?;
+
+        let now = Conservation::of_bodies(&totals, &config);
+        let initial = *run.initial.get_or_insert(now);
+        let time = run.taken as f64 * timestep.step() as f64;
+        let (step, path) = (run.taken, run.output.join("diagnostics.csv"));
+        let file = run.diagnostics.as_mut().expect("diagnostics file is opened at startup");
+        writeln!(file, "{},{:e},{:e},{:e},{:e},{:e},{:e},{:e},{:e}",
+                step, time,
+                now.kinetic_energy, now.potential_energy, now.total_energy(),
+                (now.total_energy() - initial.total_energy()) / initial.total_energy().abs(),
+                now.momentum.length(), now.angular_momentum.length(),
+                now.center_of_mass.distance(initial.center_of_mass))
+            .and_then(|_| if finished { file.flush() } else { Ok(()) })
+            .map_err(at(&path))?;
+
+        if finished {
+            info!("Finished {} steps", run.taken);
+            run.done = true;
+            exit.send(AppExit);
+        }
+        Ok(())
+    }
+
+    /// Run one physics step, unless the run is over
+    fn step_system(world: &mut World) {
+        let mut run = world.resource_mut::<HeadlessRun>();
+        if run.taken == run.steps {
+            return;
+        }
+        run.taken += 1;
+        world.run_schedule(PhysicsSchedule);
+    }
+}
+
+/// Name the file an I/O error happened on
+fn at(path: &Path) -> impl FnOnce(io::Error) -> io::Error + '_ {
+    move |err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err))
+}
+
+/// Write the mass, radius, position and velocity of every body to a CSV file
+fn write_snapshot(path: &Path, bodies: &Query<(Entity, &Mass, &Radius, &Position, &Velocity)>) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(file, "entity,mass,radius,x,y,z,vx,vy,vz")?;
+    for (entity, mass, radius, position, velocity) in bodies.iter() {
+        let (p, v) = (position.0, velocity.0);
+        writeln!(file, "{},{:e},{:e},{:e},{:e},{:e},{:e},{:e},{:e}",
+            entity.index(), mass.0, radius.0, p.x, p.y, p.z, v.x, v.y, v.z)?;
+    }
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Resource,Default)]
+    struct Steps(u64);
+
+    fn count_steps(mut steps: ResMut<Steps>) {
+        steps.0 += 1;
+    }
+
+    #[test]
+    fn runs_steps_and_writes_snapshots() {
+        let output = std::env::temp_dir().join(format!("nbody-headless-{}", std::process::id()));
+        let mut app = App::new();
+        app.add_plugin(HeadlessPlugin { steps: 5, snapshot_interval: 2, output: output.clone() })
+            .insert_resource(FixedTimestep::default())
+            .insert_resource(GravityConfig::default())
+            .init_resource::<Steps>()
+            .init_schedule(PhysicsSchedule)
+            .add_system(count_steps.in_schedule(PhysicsSchedule));
+        app.world.spawn((Mass(1.0), Radius(0.1), Position(Vec3::X), Velocity(Vec3::Y)));
+        app.world.spawn((Mass(1.0), Radius(0.1), Position(-Vec3::X), Velocity(-Vec3::Y)));
+
+        for _ in 0..10 {
+            app.update();
+        }
+
+        assert_eq!(app.world.resource::<Steps>().0, 5);
+        for step in [0, 2, 4, 5] {
+            let snapshot = fs::read_to_string(output.join(format!("snapshot_{:06}.csv", step))).unwrap();
+            assert_eq!(snapshot.lines().count(), 3);
+        }
+        assert!(!output.join("snapshot_000003.csv").exists());
+        let diagnostics = fs::read_to_string(output.join("diagnostics.csv")).unwrap();
+        assert_eq!(diagnostics.lines().count(), 5);
+        fs::remove_dir_all(output).unwrap();
+    }
+
+    #[test]
+    fn unwritable_output_is_an_error() {
+        // A file where the output directory should be
+        let output = std::env::temp_dir().join(format!("nbody-headless-file-{}", std::process::id()));
+        File::create(&output).unwrap();
+        let mut app = App::new();
+        app.add_plugin(HeadlessPlugin { steps: 1, snapshot_interval: 1, output: output.join("run") });
+
+        let mut setup = IntoSystem::into_system(HeadlessPlugin::setup_system);
+        setup.initialize(&mut app.world);
+        let err = setup.run((), &mut app.world).unwrap_err();
+        assert!(err.to_string().contains("diagnostics.csv"), "{}", err);
+        fs::remove_file(output).unwrap();
+    }
+}
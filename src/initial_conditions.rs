@@ -0,0 +1,144 @@
+use std::f32::consts::PI;
+
+use bevy::prelude::*;
+use rand::prelude::*;
+
+use crate::components::*;
+use crate::units::UnitSystem;
+
+// Initial conditions
+//
+// Generators return bodies as (mass, position, velocity) in whatever unit
+// system G is given in, so they can be fed to the solvers directly in tests as
+// well as spawned as entities.
+
+/// Density bodies are given their radius from, kg m^-3.  Far lower than real
+/// rock, so the bodies are big enough to see and to collide.
+pub const BODY_DENSITY: f64 = 2.0;
+
+/// Radius of a body of the given mass, at BODY_DENSITY
+pub fn body_radius(units: &UnitSystem, mass: f32) -> f32 {
+    let density = units.density(BODY_DENSITY);
+    let volume = mass / density;
+    ((3.0 * volume) / (4.0 * std::f32::consts::PI)).cbrt()
+}
+
+/// Spawn a body with the radius of its mass
+pub fn spawn_body(commands: &mut Commands, units: &UnitSystem, mass: f32, center: Vec3, velocity: Vec3 )
+{
+    let radius = body_radius(units, mass);
+
+    commands.spawn((
+        Position(center),
+        PreviousPosition(center),
+        Radius(radius),
+        Mass(mass),
+        Velocity(velocity),
+        Acceleration(Vec3::ZERO),
+        Potential(0.0),
+        ));
+}
+
+/// A central mass with a ring of bodies on circular orbits around it, at
+/// radii within 10% of `radius`
+pub fn stable_orbit_particles<R:Rng>(rng:&mut R, g:f32, central_mass:f32, num_bodies:usize, radius:f32) -> Vec<(f32,Vec3,Vec3)> {
+    let mut particles = Vec::new();
+
+    // Set up the center particle with mass M
+    let center_pos = Vec3::new(0.0, 0.0, 0.0);
+    let center_vel = Vec3::new(0.0, 0.0, 0.0);
+    let center_mass = central_mass;
+    let center_particle = (center_mass, center_pos, center_vel);
+    particles.push(center_particle);
+
+    // Set up the orbiting particles with random positions and velocities
+    for _ in 1..num_bodies {
+        let r = radius * (1.0 + 0.2 * (rng.gen::<f32>() - 0.5));
+        let theta = 2.0 * PI * rng.gen::<f32>();
+        let x = r * theta.cos();
+        let y = r * theta.sin();
+        let z = 0.0;
+        let pos = Vec3::new(x, y, z);
+
+        let v_circ = (g * central_mass / r).sqrt();
+        let vx = -v_circ * theta.sin();
+        let vy = v_circ * theta.cos();
+        let vz = 0.0;
+        let vel = Vec3::new(vx, vy, vz);
+
+        let mass = rng.gen_range(0.1..1.0) * 5e-5 * central_mass;
+        let particle = (mass, pos, vel);
+        particles.push(particle);
+    }
+
+    particles
+}
+
+/// Plummer sphere of the given total mass and scale radius, in virial
+/// equilibrium (Aarseth, Henon & Wielen 1974)
+pub fn plummer_sphere<R:Rng>(rng:&mut R, g:f32, total_mass:f32, num_bodies:usize, scale_radius:f32) -> Vec<(f32,Vec3,Vec3)> {
+    let mass = total_mass / num_bodies as f32;
+
+    let random_direction = |rng:&mut R| {
+        let z = rng.gen_range(-1.0f32..=1.0);
+        let phi = 2.0 * PI * rng.gen::<f32>();
+        let s = (1.0 - z * z).sqrt();
+        Vec3::new(s * phi.cos(), s * phi.sin(), z)
+    };
+
+    (0..num_bodies).map(|_| {
+        // Radius from the inverse cumulative mass profile, ignoring the
+        // sparse tail beyond 10 scale radii
+        let r = loop {
+            let x = rng.gen_range(f32::EPSILON..1.0);
+            let r = scale_radius / (x.powf(-2.0 / 3.0) - 1.0).sqrt();
+            if r < 10.0 * scale_radius {
+                break r;
+            }
+        };
+        let pos = r * random_direction(rng);
+
+        // Speed as a fraction q of the local escape speed, by rejection
+        // sampling g(q) = q^2 (1 - q^2)^3.5
+        let q = loop {
+            let q = rng.gen::<f32>();
+            if 0.1 * rng.gen::<f32>() < q * q * (1.0 - q * q).powf(3.5) {
+                break q;
+            }
+        };
+        let v_esc = (2.0 * g * total_mass / (r * r + scale_radius * scale_radius).sqrt()).sqrt();
+        let vel = q * v_esc * random_direction(rng);
+
+        (mass, pos, vel)
+    }).collect()
+}
+
+/// Bodies of equal mass at rest on a regular grid of `counts` points along
+/// each axis, `spacing` apart and centered on the origin
+pub fn grid(mass: f32, counts: [usize; 3], spacing: f32) -> Vec<(f32,Vec3,Vec3)> {
+    let [nx, ny, nz] = counts;
+    let offset = Vec3::new(nx as f32 - 1.0, ny as f32 - 1.0, nz as f32 - 1.0) * spacing / 2.0;
+    (0..nz).flat_map(|z| (0..ny).flat_map(move |y| (0..nx).map(move |x| {
+        let pos = Vec3::new(x as f32, y as f32, z as f32) * spacing - offset;
+        (mass, pos, Vec3::ZERO)
+    }))).collect()
+}
+
+/// Bodies with masses uniform in `masses`, positions uniform in the box of
+/// the given half extents around the origin, and velocity components uniform
+/// in [-max_speed, max_speed] along the axes the box extends in
+pub fn random_box<R:Rng>(rng:&mut R, num_bodies:usize, masses:(f32,f32), half_extents:Vec3, max_speed:f32) -> Vec<(f32,Vec3,Vec3)> {
+    let (min_mass, max_mass) = masses;
+    let extends = half_extents.cmpgt(Vec3::ZERO);
+    let uniform = |rng:&mut R, half:Vec3| {
+        let unit = Vec3::new(rng.gen_range(-1.0..=1.0), rng.gen_range(-1.0..=1.0), rng.gen_range(-1.0..=1.0));
+        Vec3::select(extends, unit * half, Vec3::ZERO)
+    };
+
+    (0..num_bodies).map(|_| {
+        let mass = if max_mass > min_mass { rng.gen_range(min_mass..max_mass) } else { min_mass };
+        let pos = uniform(rng, half_extents);
+        let vel = uniform(rng, Vec3::splat(max_speed));
+        (mass, pos, vel)
+    }).collect()
+}
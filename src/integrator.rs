@@ -0,0 +1,452 @@
+use std::str::FromStr;
+
+use bevy::prelude::*;
+use rayon::prelude::*;
+
+use crate::bhtree::NBody;
+use crate::forces::Forces;
+use crate::gravity::GravityConfig;
+use crate::units::UnitSystem;
+
+// Time integration schemes
+//
+// Each scheme advances a plain array copy of the bodies by one step, calling
+// back into a Forces implementation as many times as it needs.  Keeping them
+// out of the ECS lets multi-stage schemes evaluate forces at intermediate
+// states, and lets the same initial conditions be run through every scheme.
+
+/// Positions, velocities and accelerations of every body, as plain arrays
+#[derive(Clone)]
+pub struct State {
+    pub bodies: Vec<NBody>,
+    pub velocities: Vec<Vec3>,
+    /// Accelerations at the current positions
+    pub accelerations: Vec<Vec3>,
+}
+
+impl State {
+
+    /// Advance positions by `dt` at the current velocities
+    fn drift(&mut self, dt: f32) {
+        self.bodies.par_iter_mut()
+            .zip(self.velocities.par_iter())
+            .for_each(|(body, v)| body.position += dt * *v);
+    }
+
+    /// Advance velocities by `dt` at the current accelerations
+    fn kick(&mut self, dt: f32) {
+        self.velocities.par_iter_mut()
+            .zip(self.accelerations.par_iter())
+            .for_each(|(v, a)| *v += dt * *a);
+    }
+
+    /// Re-evaluate the accelerations at the current positions
+    fn evaluate(&mut self, forces: &mut dyn Forces) {
+        self.accelerations = forces.accelerations(&self.bodies);
+    }
+
+    fn positions(&self) -> Vec<Vec3> {
+        self.bodies.iter().map(|b| b.position).collect()
+    }
+
+    fn set_positions(&mut self, positions: impl IntoIterator<Item=Vec3>) {
+        for (body, p) in self.bodies.iter_mut().zip(positions) {
+            body.position = p;
+        }
+    }
+}
+
+/// A scheme advancing the bodies by one time step
+pub trait Integrator: Sync {
+    /// Advance `state` by `dt`.  On entry the accelerations must be those at
+    /// the current positions, and on exit they are those at the new ones.
+    fn step(&self, state: &mut State, dt: f32, forces: &mut dyn Forces);
+}
+
+/// Velocity Verlet, i.e. the kick-drift-kick leapfrog.  Second order and
+/// symplectic, with one force evaluation per step.
+pub struct Verlet;
+
+impl Integrator for Verlet {
+    fn step(&self, state: &mut State, dt: f32, forces: &mut dyn Forces) {
+        state.kick(0.5 * dt);
+        state.drift(dt);
+        state.evaluate(forces);
+        state.kick(0.5 * dt);
+    }
+}
+
+/// Yoshida's fourth order symplectic scheme: three Verlet steps with weights
+/// w1, w0, w1 summing to one (Yoshida 1990).  Three force evaluations per
+/// step.
+pub struct Yoshida4;
+
+impl Integrator for Yoshida4 {
+    fn step(&self, state: &mut State, dt: f32, forces: &mut dyn Forces) {
+        let cbrt2 = 2.0f32.cbrt();
+        let w1 = 1.0 / (2.0 - cbrt2);
+        let w0 = -cbrt2 * w1;
+        for w in [w1, w0, w1] {
+            Verlet.step(state, w * dt, forces);
+        }
+    }
+}
+
+/// Classical fourth order Runge-Kutta.  Not symplectic, so the energy error
+/// drifts, but very accurate over short spans.  Four force evaluations per
+/// step, the last of which is the acceleration at the new positions.
+pub struct Rk4;
+
+impl Integrator for Rk4 {
+    fn step(&self, state: &mut State, dt: f32, forces: &mut dyn Forces) {
+        let x0 = state.positions();
+        let v0 = state.velocities.clone();
+        let a0 = std::mem::take(&mut state.accelerations);
+
+        // Evaluate the acceleration at x0 + h v, returning v0 + h a
+        let mut stage = |state: &mut State, h: f32, v: &[Vec3], a: &[Vec3]| {
+            state.set_positions(x0.iter().zip(v).map(|(x, v)| *x + h * *v));
+            state.evaluate(forces);
+            let v = v0.iter().zip(a).map(|(v0, a)| *v0 + h * *a).collect::<Vec<_>>();
+            (v, std::mem::take(&mut state.accelerations))
+        };
+
+        let (v2, a2) = stage(state, 0.5 * dt, &v0, &a0);
+        let (v3, a3) = stage(state, 0.5 * dt, &v2, &a2);
+        let (v4, a4) = stage(state, dt, &v3, &a3);
+
+        let sixth = dt / 6.0;
+        let weighted = |k1: &[Vec3], k2: &[Vec3], k3: &[Vec3], k4: &[Vec3], i: usize| {
+            sixth * (k1[i] + 2.0 * k2[i] + 2.0 * k3[i] + k4[i])
+        };
+        state.set_positions((0..x0.len()).map(|i| x0[i] + weighted(&v0, &v2, &v3, &v4, i)));
+        state.velocities = (0..v0.len()).map(|i| v0[i] + weighted(&a0, &a2, &a3, &a4, i)).collect();
+        state.evaluate(forces);
+    }
+}
+
+/// Fourth order Hermite predictor-corrector (Makino & Aarseth 1992), using
+/// the jerk as well as the acceleration.  The step starts by evaluating the
+/// acceleration and jerk at the current state rather than trusting the stored
+/// accelerations, which may come from a tree solver or a different scheme.
+/// The corrector's accelerations belong to the predicted state, so the step
+/// closes with an evaluation at the new positions, for the next scheme and for
+/// the potentials and collisions kept by the force solver.  That makes two
+/// evaluations of the acceleration and jerk per step, and one of the
+/// acceleration alone.
+pub struct Hermite;
+
+impl Integrator for Hermite {
+    fn step(&self, state: &mut State, dt: f32, forces: &mut dyn Forces) {
+        let x0 = state.positions();
+        let v0 = state.velocities.clone();
+        let (a0, j0) = forces.accelerations_and_jerks(&state.bodies, &v0);
+
+        // Predict with the Taylor series
+        let (dt2, dt3) = (dt * dt, dt * dt * dt);
+        state.set_positions((0..x0.len()).map(|i| x0[i] + dt * v0[i] + dt2 / 2.0 * a0[i] + dt3 / 6.0 * j0[i]));
+        state.velocities = (0..v0.len()).map(|i| v0[i] + dt * a0[i] + dt2 / 2.0 * j0[i]).collect();
+
+        // Correct with the acceleration and jerk at the predicted state
+        let (a1, j1) = forces.accelerations_and_jerks(&state.bodies, &state.velocities);
+        state.velocities = (0..v0.len())
+            .map(|i| v0[i] + dt / 2.0 * (a0[i] + a1[i]) + dt2 / 12.0 * (j0[i] - j1[i]))
+            .collect();
+        let v1 = &state.velocities;
+        let x1: Vec<Vec3> = (0..x0.len())
+            .map(|i| x0[i] + dt / 2.0 * (v0[i] + v1[i]) + dt2 / 12.0 * (a0[i] - a1[i]))
+            .collect();
+        state.set_positions(x1);
+        state.evaluate(forces);
+    }
+}
+
+/// Kick-drift-kick leapfrog with individual, hierarchical block timesteps.
+///
+/// Each body steps at dt / 2^k for its own level k <= max_level, picked with
+/// the acceleration criterion sqrt(2 eta length / |a|) used by GADGET.  The
+/// levels nest, so every body is back in sync at the end of the step.  All
+/// bodies drift together, but only the bodies finishing their own step at a
+/// given time are kicked, and only they get a force evaluation.  A body may
+/// move to a finer level at the end of any of its steps, but only to a coarser
+/// one when the current time is on that level's grid.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct BlockTimesteps {
+    /// Accuracy parameter of the timestep criterion
+    pub eta: f32,
+    /// Length scale of the timestep criterion, normally the softening length
+    pub length: f32,
+    /// Finest level, with steps of dt / 2^max_level
+    pub max_level: u32,
+}
+
+impl BlockTimesteps {
+
+    /// The default criterion for a simulation in `units`, with the length
+    /// scale the default softening length
+    pub fn for_units(units: &UnitSystem) -> Self {
+        BlockTimesteps { eta: 0.025, length: GravityConfig::for_units(units).softening_length(), max_level: 10 }
+    }
+
+    /// Coarsest level whose step fits the criterion for acceleration `a`
+    fn level_for(&self, dt: f32, a: Vec3) -> u32 {
+        let wanted = (2.0 * self.eta * self.length / a.length()).sqrt();
+        let mut level = 0;
+        while level < self.max_level && dt / (1u32 << level) as f32 > wanted {
+            level += 1;
+        }
+        level
+    }
+}
+
+impl Default for BlockTimesteps {
+    fn default() -> Self {
+        BlockTimesteps::for_units(&UnitSystem::default())
+    }
+}
+
+impl Integrator for BlockTimesteps {
+    fn step(&self, state: &mut State, dt: f32, forces: &mut dyn Forces) {
+        // Time is counted in ticks of the finest level
+        let ticks = 1u32 << self.max_level;
+        let tick = dt / ticks as f32;
+        let stride = |level: u32| ticks >> level;
+
+        let mut levels: Vec<u32> = state.accelerations.iter().map(|a| self.level_for(dt, *a)).collect();
+        for (i, level) in levels.iter().enumerate() {
+            state.velocities[i] += 0.5 * tick * stride(*level) as f32 * state.accelerations[i];
+        }
+
+        let mut t = 0;
+        while t < ticks {
+            // Drift everything to the next time some body finishes its step
+            let finest = levels.iter().copied().max().unwrap_or(0);
+            let next = t + stride(finest);
+            state.drift((next - t) as f32 * tick);
+            t = next;
+
+            let active: Vec<usize> = (0..levels.len()).filter(|&i| t % stride(levels[i]) == 0).collect();
+            let accelerations = forces.accelerations_of(&state.bodies, &active);
+
+            for (&i, a) in active.iter().zip(accelerations) {
+                // Closing half kick of the step just finished
+                state.accelerations[i] = a;
+                state.velocities[i] += 0.5 * tick * stride(levels[i]) as f32 * a;
+                if t == ticks {
+                    continue;
+                }
+
+                // Opening half kick of the next step, on a new level
+                let wanted = self.level_for(dt, a);
+                let mut level = levels[i];
+                if wanted > level {
+                    level = wanted;
+                }
+                while level > wanted && t % stride(level - 1) == 0 {
+                    level -= 1;
+                }
+                levels[i] = level;
+                state.velocities[i] += 0.5 * tick * stride(level) as f32 * a;
+            }
+        }
+    }
+}
+
+/// Integration scheme used to advance the simulation
+#[derive(Resource,Clone,Copy,Debug,Default,PartialEq)]
+pub enum Scheme {
+    #[default]
+    Verlet,
+    Rk4,
+    Yoshida4,
+    Hermite,
+    Block(BlockTimesteps),
+}
+
+impl Scheme {
+    pub fn integrator(&self) -> &dyn Integrator {
+        match self {
+            Scheme::Verlet => &Verlet,
+            Scheme::Rk4 => &Rk4,
+            Scheme::Yoshida4 => &Yoshida4,
+            Scheme::Hermite => &Hermite,
+            Scheme::Block(block) => block,
+        }
+    }
+}
+
+impl FromStr for Scheme {
+    type Err = String;
+
+    /// Parse a scheme by name.  Block timesteps take their defaults, so the
+    /// length scale normally has to be set afterwards.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "verlet" | "leapfrog" => Ok(Scheme::Verlet),
+            "rk4" => Ok(Scheme::Rk4),
+            "yoshida4" | "yoshida" => Ok(Scheme::Yoshida4),
+            "hermite" => Ok(Scheme::Hermite),
+            "block" => Ok(Scheme::Block(BlockTimesteps::default())),
+            _ => Err(format!("unknown integrator '{}', expected verlet, rk4, yoshida4, hermite or block", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::*;
+    use crate::forces::Gravity;
+    use crate::gravity::{GravityConfig, Softening, Solver};
+    use crate::linear_octree::LinearOctree;
+
+    const G: f32 = 1.0;
+
+    fn energy(state: &State) -> f32 {
+        let bodies = &state.bodies;
+        let kinetic: f32 = bodies.iter()
+            .zip(&state.velocities)
+            .map(|(b,v)| 0.5 * b.mass * v.length_squared())
+            .sum();
+        let potential: f32 = (0..bodies.len())
+            .flat_map(|i| (i+1..bodies.len()).map(move |j| (i,j)))
+            .map(|(i,j)| -G * bodies[i].mass * bodies[j].mass / bodies[i].position.distance(bodies[j].position))
+            .sum();
+        kinetic + potential
+    }
+
+    /// Initial and final states of `orbits` circular Kepler orbits of
+    /// `steps_per_orbit` steps each
+    fn kepler(scheme: Scheme, steps_per_orbit: usize, orbits: usize) -> (State, State) {
+        let config = GravityConfig { g: G, softening: Softening::None, solver: Solver::Direct, ..default() };
+        let mut octree = LinearOctree::default();
+        let mut forces = Gravity::new(&config, &mut octree);
+
+        // Circular orbit of unit radius about the center of mass
+        let (m1, m2) = (1.0, 1e-3);
+        let m = m1 + m2;
+        let v = (G * m).sqrt();
+        let bodies = vec![
+            NBody::new(Entity::from_raw(0), Vec3::new(-m2 / m, 0.0, 0.0), m1, 0.0),
+            NBody::new(Entity::from_raw(1), Vec3::new(m1 / m, 0.0, 0.0), m2, 0.0),
+        ];
+        let accelerations = forces.accelerations(&bodies);
+        let initial = State {
+            bodies,
+            velocities: vec![Vec3::new(0.0, -v * m2 / m, 0.0), Vec3::new(0.0, v * m1 / m, 0.0)],
+            accelerations,
+        };
+
+        let mut state = initial.clone();
+        let dt = 2.0 * PI / steps_per_orbit as f32;
+        for _ in 0..orbits * steps_per_orbit {
+            scheme.integrator().step(&mut state, dt, &mut forces);
+        }
+        (initial, state)
+    }
+
+    fn kepler_energy_error(scheme: Scheme, steps_per_orbit: usize, orbits: usize) -> f32 {
+        let (initial, last) = kepler(scheme, steps_per_orbit, orbits);
+        ((energy(&last) - energy(&initial)) / energy(&initial)).abs()
+    }
+
+    /// After whole orbits the light body is back where it started
+    fn kepler_position_error(scheme: Scheme, steps_per_orbit: usize, orbits: usize) -> f32 {
+        let (initial, last) = kepler(scheme, steps_per_orbit, orbits);
+        initial.bodies[1].position.distance(last.bodies[1].position)
+    }
+
+    #[test]
+    fn schemes_conserve_energy() {
+        let block = Scheme::Block(BlockTimesteps { eta: 0.025, length: 0.01, max_level: 4 });
+        for scheme in [Scheme::Verlet, Scheme::Rk4, Scheme::Yoshida4, Scheme::Hermite, block] {
+            let error = kepler_energy_error(scheme, 100, 100);
+            assert!(error < 1e-4, "{:?}: relative energy error {:e}", scheme, error);
+        }
+    }
+
+    #[test]
+    fn verlet_bounded_over_many_orbits() {
+        let error = kepler_energy_error(Scheme::Verlet, 100, 1000);
+        assert!(error < 1e-4, "relative energy error {:e}", error);
+    }
+
+    #[test]
+    fn fourth_order_beats_verlet() {
+        let verlet = kepler_position_error(Scheme::Verlet, 20, 1);
+        for scheme in [Scheme::Rk4, Scheme::Yoshida4, Scheme::Hermite] {
+            let error = kepler_position_error(scheme, 20, 1);
+            assert!(error < verlet, "{:?}: {:e} vs Verlet {:e}", scheme, error, verlet);
+        }
+    }
+
+    #[test]
+    fn accelerations_are_at_new_positions() {
+        let block = Scheme::Block(BlockTimesteps { eta: 0.025, length: 0.01, max_level: 4 });
+        for scheme in [Scheme::Verlet, Scheme::Rk4, Scheme::Yoshida4, Scheme::Hermite, block] {
+            let (_, last) = kepler(scheme, 20, 1);
+            let config = GravityConfig { g: G, softening: Softening::None, solver: Solver::Direct, ..default() };
+            let exact = Gravity::new(&config, &mut LinearOctree::default()).accelerations(&last.bodies);
+            for (a, e) in last.accelerations.iter().zip(exact) {
+                assert!((*a - e).length() <= 1e-5 * e.length(), "{:?}: {} vs {}", scheme, a, e);
+            }
+        }
+    }
+
+    /// Counts the force evaluations of each body
+    struct Counting<'a> {
+        forces: Gravity<'a>,
+        evaluations: Vec<usize>,
+    }
+
+    impl<'a> Forces for Counting<'a> {
+        fn accelerations(&mut self, bodies: &[NBody]) -> Vec<Vec3> {
+            self.evaluations.iter_mut().for_each(|n| *n += 1);
+            self.forces.accelerations(bodies)
+        }
+
+        fn accelerations_of(&mut self, bodies: &[NBody], active: &[usize]) -> Vec<Vec3> {
+            active.iter().for_each(|&i| self.evaluations[i] += 1);
+            self.forces.accelerations_of(bodies, active)
+        }
+
+        fn accelerations_and_jerks(&mut self, bodies: &[NBody], velocities: &[Vec3]) -> (Vec<Vec3>, Vec<Vec3>) {
+            self.evaluations.iter_mut().for_each(|n| *n += 1);
+            self.forces.accelerations_and_jerks(bodies, velocities)
+        }
+    }
+
+    /// A tight binary and a distant body: the distant body takes far fewer
+    /// force evaluations than the binary
+    #[test]
+    fn block_timesteps_only_evaluate_active_bodies() {
+        let config = GravityConfig { g: G, softening: Softening::None, solver: Solver::LinearBarnesHut, ..default() };
+        let mut octree = LinearOctree::default();
+        let mut forces = Counting { forces: Gravity::new(&config, &mut octree), evaluations: vec![0; 3] };
+
+        let bodies = vec![
+            NBody::new(Entity::from_raw(0), Vec3::ZERO, 1.0, 0.0),
+            NBody::new(Entity::from_raw(1), Vec3::new(1.0, 0.0, 0.0), 1e-3, 0.0),
+            NBody::new(Entity::from_raw(2), Vec3::new(100.0, 0.0, 0.0), 1e-6, 0.0),
+        ];
+        let accelerations = forces.forces.accelerations(&bodies);
+        let mut state = State {
+            bodies,
+            velocities: vec![Vec3::ZERO, Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, 0.1, 0.0)],
+            accelerations,
+        };
+
+        let block = BlockTimesteps { eta: 0.025, length: 0.01, max_level: 8 };
+        let e0 = energy(&state);
+        for _ in 0..100 {
+            block.step(&mut state, 0.5, &mut forces);
+        }
+
+        let evaluations = &forces.evaluations;
+        assert_eq!(evaluations[2], 100, "{:?}", evaluations);
+        assert!(evaluations[1] >= 32 * 100, "{:?}", evaluations);
+        let error = ((energy(&state) - e0) / e0).abs();
+        assert!(error < 1e-3, "relative energy error {:e}", error);
+    }
+}
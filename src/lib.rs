@@ -0,0 +1,25 @@
+//! Gravitational N-body simulation on Bevy.
+//!
+//! Bodies are entities, advanced by a choice of integrators on a fixed
+//! timestep, with self-gravity from direct summation, Barnes-Hut trees or the
+//! fast multipole method.  `NBodyPlugin` adds the simulation to an app.
+
+pub mod bhtree;
+pub mod collisions;
+pub mod components;
+pub mod diagnostics;
+pub mod direct;
+pub mod fmm;
+pub mod forces;
+pub mod gravity;
+pub mod headless;
+pub mod initial_conditions;
+pub mod integrator;
+pub mod linear_octree;
+mod plugin;
+pub mod scenario;
+pub mod timestep;
+pub mod units;
+pub mod validation;
+
+pub use plugin::{NBodyPlugin, NBodySet};
@@ -0,0 +1,468 @@
+use bevy::prelude::*;
+use rayon::prelude::*;
+
+use crate::gravity::{self, GravityConfig, MultipoleOrder};
+
+// Linear (Morton ordered) Barnes-Hut octree
+//
+// Bodies are sorted along a Z-order curve with a parallel radix sort, so that
+// every node of the tree covers a contiguous range of the sorted bodies.
+// Nodes and bodies live in flat Vecs which are cleared, not freed, between
+// frames, so rebuilding the tree every frame does not churn the heap.
+
+/// Bits of precision per axis in a Morton key (3 * 21 = 63 bits)
+const MORTON_BITS: u32 = 21;
+
+struct Node {
+    /// Geometric center of the node's cube
+    center: Vec3,
+    half_size: f32,
+    first_body: usize,
+    num_bodies: usize,
+    first_child: usize,
+    num_children: usize,
+    level: u32,
+    mass: f32,
+    center_of_mass: Vec3,
+    quadrupole: Mat3,
+}
+
+impl Node {
+    fn is_leaf(&self) -> bool {
+        self.num_children == 0
+    }
+
+    fn bodies(&self) -> std::ops::Range<usize> {
+        self.first_body..self.first_body + self.num_bodies
+    }
+
+    fn children(&self) -> std::ops::Range<usize> {
+        self.first_child..self.first_child + self.num_children
+    }
+
+    fn contains(&self, p: Vec3) -> bool {
+        let d = (p - self.center).abs();
+        d.x <= self.half_size && d.y <= self.half_size && d.z <= self.half_size
+    }
+}
+
+/// Flat octree, rebuilt in place every frame
+#[derive(Resource,Default)]
+pub struct LinearOctree {
+    nodes: Vec<Node>,
+
+    // Bodies, in insertion order
+    entities: Vec<Entity>,
+    positions: Vec<Vec3>,
+    masses: Vec<f32>,
+    radii: Vec<f32>,
+
+    // Morton keys and the insertion index of each body, in sorted order
+    keys: Vec<u64>,
+    indices: Vec<u32>,
+    scratch_keys: Vec<u64>,
+    scratch_indices: Vec<u32>,
+
+    // Bodies, in Morton order
+    sorted_positions: Vec<Vec3>,
+    sorted_masses: Vec<f32>,
+    sorted_radii: Vec<f32>,
+
+    // Acceleration, potential and overlapping bodies, in Morton order
+    results: Vec<(Vec3,f32,Vec<Entity>)>,
+}
+
+impl LinearOctree {
+
+    /// Remove all bodies, keeping allocations for the next build
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+        self.entities.clear();
+        self.positions.clear();
+        self.masses.clear();
+        self.radii.clear();
+    }
+
+    pub fn push(&mut self, entity: Entity, position: Vec3, mass: f32, radius: f32) {
+        self.entities.push(entity);
+        self.positions.push(position);
+        self.masses.push(mass);
+        self.radii.push(radius);
+    }
+
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    /// Sort the bodies into Morton order, then build the nodes, with at most
+    /// `bucket_size` bodies per leaf, and their multipole moments
+    pub fn build(&mut self, bucket_size: usize) {
+        self.nodes.clear();
+        if self.is_empty() {
+            return;
+        }
+
+        // Bounding cube, slightly enlarged so no body sits on the far face
+        let (pmin, pmax) = self.positions.par_iter()
+            .fold(|| (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)), |(lo,hi),p| (lo.min(*p), hi.max(*p)))
+            .reduce(|| (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)), |(lo1,hi1),(lo2,hi2)| (lo1.min(lo2), hi1.max(hi2)));
+        let center = (pmin + pmax) * 0.5;
+        let half_size = ((pmax - pmin).max_element() * 0.5).max(f32::EPSILON) * 1.0001;
+
+        // Morton keys
+        let origin = center - Vec3::splat(half_size);
+        let scale = (1u64 << MORTON_BITS) as f32 / (2.0 * half_size);
+        self.positions.par_iter()
+            .map(|p| morton_key((*p - origin) * scale))
+            .collect_into_vec(&mut self.keys);
+        self.indices.clear();
+        self.indices.extend(0..self.len() as u32);
+
+        radix_sort(&mut self.keys, &mut self.indices, &mut self.scratch_keys, &mut self.scratch_indices);
+
+        // Gather bodies into Morton order
+        let indices = &self.indices;
+        indices.par_iter().map(|&i| self.positions[i as usize]).collect_into_vec(&mut self.sorted_positions);
+        indices.par_iter().map(|&i| self.masses[i as usize]).collect_into_vec(&mut self.sorted_masses);
+        indices.par_iter().map(|&i| self.radii[i as usize]).collect_into_vec(&mut self.sorted_radii);
+
+        self.build_nodes(center, half_size, bucket_size.max(1));
+        self.update_moments();
+    }
+
+    /// Split nodes breadth first.  Children are allocated contiguously and
+    /// always after their parent.
+    fn build_nodes(&mut self, center: Vec3, half_size: f32, bucket_size: usize) {
+        self.nodes.push(Node {
+            center,
+            half_size,
+            first_body: 0,
+            num_bodies: self.len(),
+            first_child: 0,
+            num_children: 0,
+            level: 0,
+            mass: 0.0,
+            center_of_mass: center,
+            quadrupole: Mat3::ZERO,
+        });
+
+        let mut index = 0;
+        while index < self.nodes.len() {
+            let node = &self.nodes[index];
+            if node.num_bodies <= bucket_size || node.level >= MORTON_BITS {
+                index += 1;
+                continue;
+            }
+
+            let shift = 3 * (MORTON_BITS - 1 - node.level);
+            let octant = |key: &u64| (key >> shift) & 7;
+            let (level, parent_center, child_half) = (node.level + 1, node.center, node.half_size * 0.5);
+            let range = node.bodies();
+            let keys = &self.keys[range.clone()];
+
+            let first_child = self.nodes.len();
+            let mut start = 0;
+            while start < keys.len() {
+                let digit = octant(&keys[start]);
+                let end = start + keys[start..].partition_point(|k| octant(k) == digit);
+                let offset = Vec3::new(
+                    if digit & 1 != 0 { 1.0 } else { -1.0 },
+                    if digit & 2 != 0 { 1.0 } else { -1.0 },
+                    if digit & 4 != 0 { 1.0 } else { -1.0 });
+                let center = parent_center + offset * child_half;
+                self.nodes.push(Node {
+                    center,
+                    half_size: child_half,
+                    first_body: range.start + start,
+                    num_bodies: end - start,
+                    first_child: 0,
+                    num_children: 0,
+                    level,
+                    mass: 0.0,
+                    center_of_mass: center,
+                    quadrupole: Mat3::ZERO,
+                });
+                start = end;
+            }
+
+            let num_children = self.nodes.len() - first_child;
+            let node = &mut self.nodes[index];
+            node.first_child = first_child;
+            node.num_children = num_children;
+            index += 1;
+        }
+    }
+
+    /// Compute mass, center of mass and quadrupole of every node in a single
+    /// bottom-up pass
+    fn update_moments(&mut self) {
+        for index in (0..self.nodes.len()).rev() {
+            let node = &self.nodes[index];
+            let (mass, center_of_mass, quadrupole);
+
+            if node.is_leaf() {
+                let positions = &self.sorted_positions[node.bodies()];
+                let masses = &self.sorted_masses[node.bodies()];
+                (mass, center_of_mass) = gravity::mass_and_center_of_mass(
+                    positions.iter().copied().zip(masses.iter().copied()), node.center);
+                quadrupole = positions.iter().zip(masses)
+                    .fold(Mat3::ZERO, |acc,(p,m)| acc + gravity::point_quadrupole(*m, *p - center_of_mass));
+            } else {
+                let children = &self.nodes[node.children()];
+                mass = children.iter().map(|c| c.mass).sum::<f32>();
+                center_of_mass = if mass > 0.0 {
+                    children.iter().map(|c| c.center_of_mass * c.mass).sum::<Vec3>() / mass
+                } else {
+                    node.center
+                };
+                quadrupole = children.iter()
+                    .fold(Mat3::ZERO, |acc,c| acc + c.quadrupole + gravity::point_quadrupole(c.mass, c.center_of_mass - center_of_mass));
+            }
+
+            let node = &mut self.nodes[index];
+            node.mass = mass;
+            node.center_of_mass = center_of_mass;
+            node.quadrupole = quadrupole;
+        }
+    }
+
+    /// Walk the tree for the body at sorted index `i`, without recursion,
+    /// returning its acceleration, its potential if enabled in the config, and
+    /// the bodies it overlaps
+    fn calculate_acceleration(&self, i: usize, stack: &mut Vec<usize>, config: &GravityConfig) -> (Vec3, f32, Vec<Entity>) {
+        let position = self.sorted_positions[i];
+        let radius = self.sorted_radii[i];
+        let mut accel = Vec3::ZERO;
+        let mut potential = 0.0;
+        let mut collided_with = Vec::new();
+
+        stack.clear();
+        stack.push(0);
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            let open = node.contains(position)
+                || config.should_open(2.0 * node.half_size, node.center, node.center_of_mass, position);
+
+            if open && node.is_leaf() {
+                for j in node.bodies() {
+                    if i == j {
+                        continue;
+                    }
+                    let diff = self.sorted_positions[j] - position;
+                    let dist2 = diff.length_squared();
+                    let radii = radius + self.sorted_radii[j];
+                    accel += config.pair_acceleration(diff, self.sorted_masses[j]);
+                    if config.potential && dist2 > 0.0 {
+                        potential += self.sorted_masses[j] * config.pair_potential(dist2);
+                    }
+                    if dist2 < radii * radii {
+                        collided_with.push(self.entities[self.indices[j] as usize]);
+                    }
+                }
+            }
+            else if open {
+                stack.extend(node.children());
+            }
+            else {
+                let r = position - node.center_of_mass;
+                accel += config.pair_acceleration(-r, node.mass);
+                if config.potential {
+                    potential += node.mass * config.pair_potential(r.length_squared());
+                }
+                if config.multipole_order == MultipoleOrder::Quadrupole {
+                    let r2 = config.softened_dist2(r.length_squared());
+                    accel += gravity::quadrupole_acceleration(config.g, &node.quadrupole, r, r2);
+                    if config.potential {
+                        potential += gravity::quadrupole_potential(config.g, &node.quadrupole, r, r2);
+                    }
+                }
+            }
+        }
+
+        (accel, potential, collided_with)
+    }
+
+    /// Compute the acceleration of every body, in parallel over bodies in
+    /// Morton order so neighbouring threads walk similar paths
+    pub fn compute_accelerations(&mut self, config: &GravityConfig) {
+        let mut results = std::mem::take(&mut self.results);
+        (0..self.len()).into_par_iter()
+            .map_init(Vec::new, |stack, i| self.calculate_acceleration(i, stack, config))
+            .collect_into_vec(&mut results);
+        self.results = results;
+    }
+
+    /// Compute the acceleration, potential and overlapping bodies of only the
+    /// bodies at the given insertion indices, in the same order, without
+    /// touching the stored results
+    pub fn accelerations_of(&self, active: &[usize], config: &GravityConfig) -> Vec<(Vec3,f32,Vec<Entity>)> {
+        let mut sorted_index = vec![0; self.len()];
+        for (sorted, &i) in self.indices.iter().enumerate() {
+            sorted_index[i as usize] = sorted;
+        }
+        active.par_iter()
+            .map_init(Vec::new, |stack, &i| self.calculate_acceleration(sorted_index[i], stack, config))
+            .collect()
+    }
+
+    /// Results of the last compute_accelerations(), as (entity, acceleration,
+    /// potential, overlapping bodies)
+    pub fn accelerations(&self) -> impl Iterator<Item=(Entity,Vec3,f32,&[Entity])> {
+        self.indices.iter()
+            .zip(self.results.iter())
+            .map(|(&i,(accel,potential,collisions))| (self.entities[i as usize], *accel, *potential, collisions.as_slice()))
+    }
+}
+
+/// Spread the low 21 bits of `v` so there are two zero bits between each
+fn spread_bits(v: u64) -> u64 {
+    let mut x = v & 0x1f_ffff;
+    x = (x | x << 32) & 0x001f_0000_0000_ffff;
+    x = (x | x << 16) & 0x001f_0000_ff00_00ff;
+    x = (x | x << 8)  & 0x100f_00f0_0f00_f00f;
+    x = (x | x << 4)  & 0x10c3_0c30_c30c_30c3;
+    x = (x | x << 2)  & 0x1249_2492_4924_9249;
+    x
+}
+
+/// Interleave quantized coordinates into a Morton key.  The octant order
+/// (x^0 y^1 z^2) matches BBox3::subdivide().
+fn morton_key(q: Vec3) -> u64 {
+    let max = ((1u64 << MORTON_BITS) - 1) as f32;
+    let q = q.clamp(Vec3::ZERO, Vec3::splat(max));
+    spread_bits(q.x as u64) | spread_bits(q.y as u64) << 1 | spread_bits(q.z as u64) << 2
+}
+
+/// Raw pointer which may be shared between threads writing disjoint elements
+struct SharedPtr<T>(*mut T);
+unsafe impl<T> Send for SharedPtr<T> {}
+unsafe impl<T> Sync for SharedPtr<T> {}
+
+impl<T> SharedPtr<T> {
+    fn get(&self) -> *mut T {
+        self.0
+    }
+}
+
+/// Parallel LSD radix sort of keys and their values, 8 bits per pass.  Each
+/// pass histograms chunks in parallel, then scatters each chunk in parallel
+/// to its precomputed, disjoint output offsets.
+fn radix_sort(keys: &mut Vec<u64>, values: &mut Vec<u32>, scratch_keys: &mut Vec<u64>, scratch_values: &mut Vec<u32>) {
+    let n = keys.len();
+    scratch_keys.resize(n, 0);
+    scratch_values.resize(n, 0);
+
+    let chunk_size = (n / (4 * rayon::current_num_threads())).max(4096);
+    let passes = (3 * MORTON_BITS).div_ceil(8);
+
+    for pass in 0..passes {
+        let shift = 8 * pass;
+        let digit = |key: u64| ((key >> shift) & 0xff) as usize;
+
+        let histograms: Vec<[usize; 256]> = keys.par_chunks(chunk_size)
+            .map(|chunk| {
+                let mut histogram = [0; 256];
+                for &key in chunk {
+                    histogram[digit(key)] += 1;
+                }
+                histogram
+            })
+            .collect();
+
+        // Exclusive prefix sum in digit-major, chunk-minor order keeps the
+        // sort stable
+        let mut offsets = vec![[0usize; 256]; histograms.len()];
+        let mut sum = 0;
+        for d in 0..256 {
+            for (offset, histogram) in offsets.iter_mut().zip(histograms.iter()) {
+                offset[d] = sum;
+                sum += histogram[d];
+            }
+        }
+
+        let out_keys = SharedPtr(scratch_keys.as_mut_ptr());
+        let out_values = SharedPtr(scratch_values.as_mut_ptr());
+        keys.par_chunks(chunk_size)
+            .zip(values.par_chunks(chunk_size))
+            .zip(offsets.into_par_iter())
+            .for_each(|((key_chunk, value_chunk), mut offset)| {
+                for (&key, &value) in key_chunk.iter().zip(value_chunk) {
+                    let d = digit(key);
+                    // SAFETY: offsets partition 0..n, so every write is in
+                    // bounds and no two chunks write the same element
+                    unsafe {
+                        *out_keys.get().add(offset[d]) = key;
+                        *out_values.get().add(offset[d]) = value;
+                    }
+                    offset[d] += 1;
+                }
+            });
+
+        std::mem::swap(keys, scratch_keys);
+        std::mem::swap(values, scratch_values);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::prelude::*;
+
+    #[test]
+    fn morton_keys_interleave_xyz() {
+        assert_eq!(morton_key(Vec3::new(1.0, 0.0, 0.0)), 0b001);
+        assert_eq!(morton_key(Vec3::new(0.0, 1.0, 0.0)), 0b010);
+        assert_eq!(morton_key(Vec3::new(0.0, 0.0, 1.0)), 0b100);
+        assert_eq!(morton_key(Vec3::new(3.0, 0.0, 2.0)), 0b101_001);
+        assert_eq!(spread_bits(0x1f_ffff), 0x1249_2492_4924_9249);
+        // Out of range coordinates are clamped to the cube
+        assert_eq!(morton_key(Vec3::splat(-1.0)), 0);
+        assert_eq!(morton_key(Vec3::splat(1e9)), (1 << 63) - 1);
+    }
+
+    #[test]
+    fn bodies_are_in_key_order() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut octree = LinearOctree::default();
+        for i in 0..5000 {
+            let position = Vec3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0));
+            octree.push(Entity::from_raw(i), position, 1.0, 0.0);
+        }
+        octree.build(8);
+
+        assert!(octree.keys.windows(2).all(|pair| pair[0] <= pair[1]));
+        let mut indices = octree.indices.clone();
+        indices.sort_unstable();
+        assert!(indices.iter().copied().eq(0..5000));
+        for (sorted, &i) in octree.indices.iter().enumerate() {
+            assert_eq!(octree.sorted_positions[sorted], octree.positions[i as usize]);
+        }
+        // Every leaf holds only bodies inside its cube
+        for node in octree.nodes.iter().filter(|node| node.is_leaf()) {
+            assert!(node.bodies().all(|j| node.contains(octree.sorted_positions[j])));
+        }
+    }
+
+    #[test]
+    fn radix_sort_is_a_stable_permutation() {
+        let mut rng = StdRng::seed_from_u64(1);
+        // Several chunks, and many equal keys differing in every byte
+        let n = 50000;
+        let mut keys: Vec<u64> = (0..n).map(|_| rng.gen_range(0..64u64) * 0x0080_8080_8080_8080).collect();
+        let mut values: Vec<u32> = (0..n as u32).collect();
+        let original = keys.clone();
+
+        radix_sort(&mut keys, &mut values, &mut Vec::new(), &mut Vec::new());
+
+        for (key, &value) in keys.iter().zip(&values) {
+            assert_eq!(*key, original[value as usize]);
+        }
+        for (k, v) in keys.windows(2).zip(values.windows(2)) {
+            assert!(k[0] < k[1] || (k[0] == k[1] && v[0] < v[1]));
+        }
+    }
+}
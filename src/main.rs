@@ -1,105 +1,221 @@
-use std::f32::consts::PI;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::str::FromStr;
 
-use bevy::{prelude::*, diagnostic::{LogDiagnosticsPlugin, FrameTimeDiagnosticsPlugin}};
+use bevy::{prelude::*, app::AppExit, diagnostic::{LogDiagnosticsPlugin, FrameTimeDiagnosticsPlugin}, log::LogPlugin};
 use bevy_prototype_lyon::prelude::*;
-use bhtree::{BBox3};
-use components::*;
-use rand::prelude::*;
-
-mod components;
-mod bhtree;
-
-fn main() {
-    App::new()
-        .add_plugins(DefaultPlugins)
-        .add_plugin(ShapePlugin)
-        .add_plugin(LogDiagnosticsPlugin::default())
-        .add_plugin(FrameTimeDiagnosticsPlugin::default())
-        .add_startup_system(setup_global)
-        .add_startup_system(setup_bodies)
-        .add_system(player_camera_control)
-        .add_system(bh_gravity_acceleration_system)
-        .add_system(apply_acceleration_system.after(bh_gravity_acceleration_system))
-        .add_system(movement_system.after(apply_acceleration_system))
-        .add_system(position_update_system.after(movement_system))
-        .add_system(direction_update_system.after(apply_acceleration_system))
-        .run();
+use clap::{error::ErrorKind, CommandFactory, Parser};
+use nbody::bhtree::NBody;
+use nbody::collisions::CollisionResponse;
+use nbody::components::*;
+use nbody::diagnostics::ConservationDiagnosticsPlugin;
+use nbody::gravity::{GravityConfig, Solver};
+use nbody::headless::HeadlessPlugin;
+use nbody::initial_conditions::spawn_body;
+use nbody::integrator::{BlockTimesteps, Scheme};
+use nbody::scenario::Scenario;
+use nbody::timestep::{self, FixedTimestep};
+use nbody::units::{self, UnitSystem};
+use nbody::{validation, NBodyPlugin, NBodySet};
+
+/// N-body gravity simulation, in a window or headless
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Scenario file with the initial conditions, a ring around a star if not
+    /// given
+    scenario: Option<PathBuf>,
+    /// Number of bodies of each disk, sphere and random generator in the
+    /// scenario
+    #[arg(short = 'n', long, value_parser = positive::<usize>)]
+    bodies: Option<usize>,
+    /// Central mass of each disk, in the scenario's units
+    #[arg(long, value_parser = positive::<f32>)]
+    central_mass: Option<f32>,
+    /// Radius of each disk, in the scenario's units
+    #[arg(long, value_parser = positive::<f32>)]
+    disk_radius: Option<f32>,
+    /// Seed of the random initial conditions
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Opening angle of the tree solvers
+    #[arg(long, value_parser = positive::<f32>)]
+    theta: Option<f32>,
+    /// Simulated time per physics step, in the time unit of --units.  This is
+    /// the integration timestep, and overrides the scenario's.
+    #[arg(long, value_parser = positive::<f32>)]
+    dt: Option<f32>,
+    /// Simulated time per wall clock second in a window, in the time unit of
+    /// --units.  Together with --dt it sets how many steps run per second.
+    /// Overrides the scenario's.
+    #[arg(long, value_parser = positive::<f32>)]
+    time_scale: Option<f32>,
+    /// Integration scheme: verlet, rk4, yoshida4, hermite or block.  Hermite
+    /// always takes its forces from direct summation.
+    #[arg(long)]
+    integrator: Option<Scheme>,
+    /// Gravity solver: barnes-hut, linear, fmm or direct
+    #[arg(long)]
+    solver: Option<Solver>,
+    /// Unit system to simulate in: si, astronomical or nbody.  The scenario's
+    /// if not given.
+    #[arg(long)]
+    units: Option<UnitSystem>,
+    /// Physics steps to run before exiting
+    #[arg(long)]
+    steps: Option<u64>,
+    /// Run without a window, as fast as possible, writing snapshots and
+    /// diagnostics to the output directory
+    #[arg(long, requires = "steps")]
+    headless: bool,
+    /// Directory headless runs write to
+    #[arg(long, default_value = "output")]
+    output: PathBuf,
 }
 
-const G: f32 = 6.674*10e-11;
-const SPEED: f32 = 10e4;
-const OMEGA: f32 = 1.0;    // Ignore gravity calculations on bodies closer than this to each other
-
-fn gravity_acceleration_system(
-    mut q: Query<(&Position, &Mass, &Radius, &mut Acceleration)>,
-) {
-
-    let mut others: Vec<(&Position, &Mass, &Radius, Mut<Acceleration>)> = Vec::new();
-
-    for (pos, mass, radius, mut accel) in q.iter_mut() {
-
-        accel.0 = Vec3::ZERO;
-
-        for (opos, omass, oradius, oaccel) in others.iter_mut() {
-            
-            let diff = opos.0 - pos.0;
-            let dist2 = diff.length_squared();
-            let radii = radius.0 + oradius.0;
-            let radii2 = radii*radii;
+/// Parse a number greater than zero
+fn positive<T>(s: &str) -> Result<T, String>
+where
+    T: FromStr + PartialOrd + Default,
+    T::Err: Display,
+{
+    let value: T = s.parse().map_err(|err: T::Err| err.to_string())?;
+    if value > T::default() {
+        Ok(value)
+    } else {
+        Err(format!("{} is not positive", s))
+    }
+}
 
-            if dist2 > radii2 {
-                if let Some(dir) = diff.try_normalize() {
-                    let f  = G * omass.0 / dist2;
-                    let of = G *  mass.0 / dist2;
-                    
-                    oaccel.0 -= of * dir;
-                }
-            }
-        }
-        others.push( (pos,mass,radius,accel) );
+/// Exit with a usage error about `arg`
+fn invalid_argument(arg: &str, err: impl Display) -> ! {
+    Cli::command().error(ErrorKind::ValueValidation, format!("{}: {}", arg, err)).exit()
+}
 
+fn main() {
+    let cli = Cli::parse();
+
+    // Initial conditions from the scenario, adjusted by the arguments
+    let mut scenario = match &cli.scenario {
+        Some(path) => Scenario::load(path).unwrap_or_else(|err| invalid_argument(&path.display().to_string(), err)),
+        None => Scenario::default(),
+    };
+    if let Some(n) = cli.bodies {
+        scenario.set_count(n).unwrap_or_else(|err| invalid_argument("--bodies", err));
+    }
+    if let Some(mass) = cli.central_mass {
+        scenario.set_central_mass(mass).unwrap_or_else(|err| invalid_argument("--central-mass", err));
+    }
+    if let Some(radius) = cli.disk_radius {
+        scenario.set_disk_radius(radius).unwrap_or_else(|err| invalid_argument("--disk-radius", err));
+    }
+    if cli.seed.is_some() {
+        scenario.seed = cli.seed;
+    }
+    let units = cli.units.unwrap_or_else(|| scenario.unit_system().expect("scenario is validated"));
+    let (size, mass) = scenario.extent();
+    if let Err(err) = units.check_scale(size, mass) {
+        invalid_argument(if cli.units.is_some() { "--units" } else { "units" }, err);
     }
-}
 
-fn bh_gravity_acceleration_system(
-    mut q: Query<(Entity, &Position, &Mass, &Radius, &mut Acceleration)>,
-) {
+    let mut app = App::new();
+    match cli.steps {
+        Some(steps) if cli.headless => {
+            app.add_plugins(MinimalPlugins)
+                .add_plugin(LogPlugin::default())
+                .add_plugin(HeadlessPlugin { steps, snapshot_interval: SNAPSHOT_INTERVAL, output: cli.output });
+        },
+        _ => {
+            app.add_plugins(DefaultPlugins)
+                .add_plugin(ShapePlugin)
+                .add_plugin(LogDiagnosticsPlugin::default())
+                .add_plugin(FrameTimeDiagnosticsPlugin)
+                .add_plugin(ConservationDiagnosticsPlugin::default())
+                .add_startup_system(setup_camera)
+                .add_system(player_camera_control)
+                .add_system(solver_select_control)
+                .add_system(validation_report_control)
+                .add_system(integrator_select_control)
+                .add_system(collision_select_control)
+                .add_system(timestep::run_physics_schedule)
+                .add_system(step_limit_system.after(timestep::run_physics_schedule))
+                .add_systems((position_update_system, direction_update_system, body_shape_system)
+                    .chain()
+                    .in_set(NBodySet::SyncTransforms));
+        },
+    }
 
-    let bounds = BBox3::from( q.iter().map(|(_,p,_,_,_)| &p.0));    
-    let bhtree = bhtree::BHTreeNode::from(&bounds, q.iter().map(|(e,p,m,r,_)| (e,p,m,r)));
+    let mut config = GravityConfig { potential: true, ..GravityConfig::for_units(&units) };
+    if let Some(theta) = cli.theta {
+        config.theta = theta;
+    }
+    if let Some(solver) = cli.solver.or_else(|| scenario.solver().expect("scenario is validated")) {
+        config.solver = solver;
+    }
+    // Run settings come from the arguments, then the scenario, then the
+    // defaults
+    let timestep = scenario.timestep.clone().unwrap_or_default();
+    let time_scale = cli.time_scale
+        .or_else(|| scenario.time_scale_in(&units))
+        .unwrap_or_else(|| units.time(TIME_SCALE));
+    // dt is the simulated step, but FixedTimestep counts wall clock seconds
+    let dt = cli.dt
+        .or_else(|| scenario.dt_in(&units))
+        .map_or(1.0 / 60.0, |step| step / time_scale);
+    let scheme = match cli.integrator.or_else(|| scenario.scheme().expect("scenario is validated")) {
+        Some(Scheme::Block(block)) => Scheme::Block(BlockTimesteps { length: config.softening_length(), ..block }),
+        Some(scheme) => scheme,
+        None => Scheme::default(),
+    };
+
+    let mut fixed_timestep = FixedTimestep::new(dt, timestep.max_substeps.unwrap_or(4), time_scale);
+    if !cli.headless {
+        fixed_timestep.steps_left = cli.steps;
+    }
 
-    bhtree.collect_accelerations().iter()
-        .for_each(|(ent,newaccel,collisions)| {
-            if let Ok(mut accel) = q.get_component_mut::<Acceleration>(*ent) {
-                accel.0 = *newaccel;
-            }
-        });
+    app.insert_resource(units)
+        .insert_resource(fixed_timestep)
+        .insert_resource(config)
+        .insert_resource(scheme)
+        .insert_resource(scenario)
+        .add_plugin(NBodyPlugin)
+        .add_startup_system(setup_bodies);
+    app.run();
 }
 
-
-fn apply_acceleration_system(
-    time: Res<Time>,
-    mut q: Query<(&mut Velocity, &Acceleration)>
-) {
-    for (mut v, acc) in q.iter_mut() {
-        v.0 += SPEED * time.delta_seconds() * acc.0;
-    }
+/// Simulated seconds per wall clock second: the ring orbits in 40 seconds
+const TIME_SCALE: f64 = units::YEAR / 40.0;
+/// Coefficient of restitution when bodies bounce off each other
+const RESTITUTION: f32 = 0.5;
+/// Physics steps between snapshots when running headless
+const SNAPSHOT_INTERVAL: u64 = 100;
+/// World units per meter when drawing
+const WORLD_SCALE: f64 = 400.0 / units::AU;
+
+/// World units per length unit when drawing
+fn world_scale(units: &UnitSystem) -> f32 {
+    (WORLD_SCALE * units.length_unit()) as f32
 }
 
-fn movement_system(
-    time: Res<Time>,
-    mut q: Query<(&mut Position, &Velocity)>,
-) {
-    for (mut position, velocity) in q.iter_mut() {
-        position.0 += SPEED * time.delta_seconds() * velocity.0;
+/// Exit a windowed run once the requested number of steps have run
+fn step_limit_system(timestep: Res<FixedTimestep>, mut exit: EventWriter<AppExit>) {
+    if timestep.steps_left == Some(0) {
+        info!("Finished the requested steps");
+        exit.send(AppExit);
     }
 }
 
+/// Place each body between its previous and current physics positions,
+/// according to how far the frame is into the next step
 fn position_update_system(
-    mut q: Query<(&mut Transform, &Position)>,
+    timestep: Res<FixedTimestep>,
+    units: Res<UnitSystem>,
+    mut q: Query<(&mut Transform, &PreviousPosition, &Position)>,
 ) {
-    for (mut transform, position ) in q.iter_mut() {
-        transform.translation = position.0;
+    let alpha = timestep.alpha();
+    let scale = world_scale(&units);
+    for (mut transform, previous, position ) in q.iter_mut() {
+        transform.translation = previous.0.lerp(position.0, alpha) * scale;
     }
 }
 
@@ -114,35 +230,15 @@ fn direction_update_system(
     }
 }
 
-// fn collision_system(
-//     mut q: Query<(&Entity, &Transform, &Radius, &mut Velocity)>
-// ) {
-//     let other = Vec::new();
-//     for (e,t,r,v,a) in q.iter() {
-//         for (oe,ot,or, mut oa) in other.iter() {
-//             collide_dist = or + r;
-//             dist2 = (ot.translation - t.translation).length_squared();
-//             if collide_dist <= dist2 && collide_dist <= dist2.sqrt() {
-//                 let dv = ov.0 + v.0;
-
-//             }
-//         }
-//         other.push( (e,t,r,v,a) );
-//     }
-// }
-
-// const MSOL : f32 = 1.989e+30;
-// const MSOL : f32 = 1989.0;
-// const MEARTH : f32 = MSOL / 333_000.0;
-// const MVENUS : f32 = MSOL /   1_047.0;
-
-//const AU : f32 = 149_597_870.7 * 1000.0;
-const AU : f32 = 149.0;
-
-fn setup_global(mut commands: Commands)
+fn setup_camera(mut commands: Commands, scenario: Res<Scenario>, units: Res<UnitSystem>)
 {
     let mut camera = Camera2dBundle::default();
-    camera.projection.scale = 5.0;
+    camera.projection.scale = scenario.camera.as_ref().and_then(|c| c.scale).unwrap_or(5.0);
+    if let Some(center) = scenario.camera_center_in(&units) {
+        let center = center * world_scale(&units);
+        camera.transform.translation.x = center.x;
+        camera.transform.translation.y = center.y;
+    }
 
     commands
         .spawn( camera )
@@ -184,122 +280,130 @@ fn player_camera_control(kb: Res<Input<KeyCode>>, time: Res<Time>, mut query: Qu
     }
 }
 
-fn stable_orbit_particles(central_mass:f32, num_bodies:usize, radius:f32) -> Vec<(f32,Vec3,Vec3)> {
-    let mut particles = Vec::new();
-    let mut rng = rand::thread_rng();
-
-    // Set up the center particle with mass M
-    let center_pos = Vec3::new(0.0, 0.0, 0.0);
-    let center_vel = Vec3::new(0.0, 0.0, 0.0);
-    let center_mass = central_mass;
-    let center_particle = (center_mass, center_pos, center_vel);
-    particles.push(center_particle);
-
-    // Set up the orbiting particles with random positions and velocities
-    for _ in 1..num_bodies {
-        let r = radius * (1.0 + 0.2 * (rng.gen::<f32>() - 0.5));
-        let theta = 2.0 * PI * rng.gen::<f32>();
-        let x = r * theta.cos();
-        let y = r * theta.sin();
-        let z = 0.0;
-        let pos = Vec3::new(x, y, z);
-
-        let v_circ = (G * central_mass / r).sqrt();
-        let vx = -v_circ * theta.sin();
-        let vy = v_circ * theta.cos();
-        let vz = 0.0;
-        let vel = Vec3::new(vx, vy, vz);
-
-        let mass = rng.gen_range(0.1..1.0) * 10.0;
-        let particle = (mass, pos, vel);
-        particles.push(particle);
+/// Switch gravity solver with the function keys
+fn solver_select_control(kb: Res<Input<KeyCode>>, mut config: ResMut<GravityConfig>) {
+    let solver = if kb.just_pressed(KeyCode::F1) {
+        Solver::BarnesHut
+    } else if kb.just_pressed(KeyCode::F2) {
+        Solver::LinearBarnesHut
+    } else if kb.just_pressed(KeyCode::F3) {
+        Solver::Fmm
+    } else if kb.just_pressed(KeyCode::F4) {
+        Solver::Direct
+    } else {
+        return;
+    };
+
+    if config.solver != solver {
+        info!("Switching gravity solver to {:?}", solver);
+        config.solver = solver;
     }
-
-    particles
 }
 
-
-fn setup_bodies(mut commands: Commands)
-{
-
-    // // SOL
-    // setup_body(&mut commands, 1.0 * MSOL, Vec3::ZERO, Vec3::ZERO);
-
-    // // EARTH
-    // setup_body(&mut commands, MEARTH, Vec3::new(1.0*AU, 0.0), Vec3::new( 0.0, 10.0) );
-
-    for (mass, pos, deltav) in stable_orbit_particles(200000.0, 10000, 400.0) {
-         setup_body(&mut commands, mass, pos, deltav );
+/// Switch integration scheme with the function keys
+fn integrator_select_control(kb: Res<Input<KeyCode>>, config: Res<GravityConfig>, mut scheme: ResMut<Scheme>) {
+    let selected = if kb.just_pressed(KeyCode::F5) {
+        Scheme::Verlet
+    } else if kb.just_pressed(KeyCode::F6) {
+        Scheme::Rk4
+    } else if kb.just_pressed(KeyCode::F7) {
+        Scheme::Yoshida4
+    } else if kb.just_pressed(KeyCode::F8) {
+        Scheme::Hermite
+    } else if kb.just_pressed(KeyCode::F9) {
+        Scheme::Block(BlockTimesteps { length: config.softening_length(), ..default() })
+    } else {
+        return;
+    };
+
+    if *scheme != selected {
+        info!("Switching integration scheme to {:?}", selected);
+        *scheme = selected;
     }
+}
 
+/// Switch between merging and bouncing colliding bodies with the function keys
+fn collision_select_control(kb: Res<Input<KeyCode>>, mut response: ResMut<CollisionResponse>) {
+    let selected = if kb.just_pressed(KeyCode::F10) {
+        CollisionResponse::Merge
+    } else if kb.just_pressed(KeyCode::F11) {
+        CollisionResponse::Bounce { restitution: RESTITUTION }
+    } else {
+        return;
+    };
+
+    if *response != selected {
+        info!("Switching collision response to {:?}", selected);
+        *response = selected;
+    }
+}
 
-    // let mut rng = rand::thread_rng();
-
-    // let rotation = Quat::from_rotation_z(PI/2.0);     // i.e., 90 degrees around Z axis
-    // let rot_90 = Mat4::from_quat(rotation);
-
-    // for x in 1..=100 {
-    //     for y in 1..=100 {
-    //         let mass = 600_000.0;
-    //         let pos = Vec3::new( x as f32 * 100.0, y as f32 * 100.0, 0.0 );
-    //         let deltav = Vec3::ZERO;
-
-    //         setup_body(&mut commands, mass, pos, deltav);
-    //     }
-    // }
+/// Log the force error of the current solver against direct summation, for
+/// a range of opening angles
+fn validation_report_control(
+    kb: Res<Input<KeyCode>>,
+    config: Res<GravityConfig>,
+    q: Query<(Entity, &Position, &Mass, &Radius)>,
+) {
+    if !kb.just_pressed(KeyCode::F12) {
+        return;
+    }
 
-    // for _ in 1..=10000 {
-    //     let mass = rng.gen_range( 2000.0 ..= 20000000.0 );
-    //     let pos = Vec3::new( rng.gen_range( -40000.0 ..= 40000.0 ), rng.gen_range( -40000.0 ..= 40000.0 ), 0.0 );
+    let bodies: Vec<NBody> = q.iter().map(|(e,p,m,r)| NBody::new(e,p.0,m.0,r.0)).collect();
+    info!("Force error of {:?} against direct summation, {} bodies", config.solver, bodies.len());
+    for (theta, stats) in validation::error_vs_theta(&bodies, &config, &[0.3, 0.5, 0.7, 1.0]) {
+        info!("  theta {:.1}: {}", theta, stats);
+    }
+}
 
-    //     let distance_to_origin = pos.distance(Vec3::ZERO);
-    //     let dir = rot_90.transform_vector3((Vec3::ZERO - pos).normalize());
-    //     let speed = 250.0;
-    //     let deltav = dir*speed;
 
-    //     //let deltav = Vec3::new( rng.gen_range( -10.0 ..= 10.0 ), rng.gen_range( -10.0 ..= 10.0 ), 0.0 );
-    //     //let deltav = Vec3::ZERO;
+/// Spawn the bodies of the scenario
+fn setup_bodies(mut commands: Commands, scenario: Res<Scenario>, units: Res<UnitSystem>)
+{
+    for (mass, pos, velocity) in scenario.bodies_in(&mut scenario.rng(), &units) {
+        spawn_body(&mut commands, &units, mass, pos, velocity);
+    }
+}
 
-    //     setup_body(&mut commands, mass, pos, deltav );
-    // }
+/// Give new bodies a shape to draw, and rebuild it when their radius changes
+fn body_shape_system(
+    mut commands: Commands,
+    units: Res<UnitSystem>,
+    q: Query<(Entity, &Position, &Radius, Option<&Path>), Changed<Radius>>,
+) {
+    let scale = world_scale(&units);
+    for (entity, position, radius, drawn) in q.iter() {
+        let path = body_shape(radius.0, scale);
+        if drawn.is_some() {
+            commands.entity(entity).insert(path);
+            continue;
+        }
 
+        let center = position.0;
+        let transform = Transform::from_translation( Vec3::new( center.x, center.y, 0.0 ) * scale );
+        commands.entity(entity).insert((
+            ShapeBundle {
+                path,
+                transform,
+                ..default()
+            },
+            Stroke::new(Color::WHITE, 1.0),
+            Fill::color(Color::WHITE),
+        ));
+    }
 }
 
-fn setup_body(commands: &mut Commands, mass_kg: f32, center: Vec3, deltav_mps: Vec3 )
-{
-    let density = 10.0;
-    let volume = mass_kg / density;
-    let radius = ((3.0 * volume) / (4.0 * std::f32::consts::PI)).cbrt();
-
-    let components = (
-        Position(center),
-        Radius(radius),
-        Mass(mass_kg),
-        Velocity(deltav_mps),
-        Acceleration(Vec3::ZERO),
-        );
-
+/// Outline of a body, with a tick showing its direction of travel
+fn body_shape(radius: f32, scale: f32) -> Path {
     let surface = shapes::Circle {
             center: Vec2::ZERO,
-            radius };
+            radius: radius * scale };
     let dir = shapes::Line(
-            Vec2::new(radius, 0.0),
-            Vec2::new(radius + (radius * 0.50), 0.0) );
-    let path = ShapePath::new()
+            Vec2::new(radius * scale, 0.0),
+            Vec2::new(1.5 * radius * scale, 0.0) );
+    ShapePath::new()
         .add(&surface)
         .add(&dir)
         .build()
-        ;
-    
-    let transform = Transform::from_translation( Vec3::new( center.x, center.y, 0.0 ) );
-    
-    commands.spawn((
-        ShapeBundle {
-            path,
-            transform,
-            ..default()
-        },
-        Stroke::new(Color::WHITE, 1.0),
-        Fill::color(Color::WHITE),
-    )).insert(components);
 }
+
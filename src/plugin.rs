@@ -0,0 +1,235 @@
+use bevy::prelude::*;
+
+use crate::bhtree::NBody;
+use crate::collisions::{self, CollisionEvent, CollisionResponse, Collisions};
+use crate::components::*;
+use crate::diagnostics::ConservationDiagnosticsPlugin;
+use crate::forces::{ExternalFields, Forces, Gravity};
+use crate::gravity::{GravityConfig, Solver};
+use crate::integrator::{Scheme, State};
+use crate::linear_octree::LinearOctree;
+use crate::timestep::{self, FixedTimestep, PhysicsSchedule};
+use crate::units::UnitSystem;
+
+/// The simulation itself: resources, the physics schedule and its systems.
+///
+/// Resources already in the app when the plugin is added are kept, so apps
+/// configure the simulation by inserting them first.  The gravity config
+/// defaults to the one for the inserted UnitSystem.  The physics schedule is
+/// not run by the plugin: windowed apps add `timestep::run_physics_schedule`,
+/// and headless ones the `HeadlessPlugin`.  Bodies are entities with
+/// Position, PreviousPosition, Mass, Radius, Velocity, Acceleration and
+/// Potential, as spawned by `initial_conditions::spawn_body`.
+///
+/// Apps hook their own systems in at the points given by `NBodySet`.
+pub struct NBodyPlugin;
+
+/// Stages of the simulation other systems can be ordered against
+///
+/// Forces, Integrate and Collisions run in that order in the PhysicsSchedule,
+/// once per step.  SyncTransforms and Diagnostics run in that order in the
+/// main schedule, after `timestep::run_physics_schedule`.
+#[derive(SystemSet,Clone,Copy,Debug,PartialEq,Eq,Hash)]
+pub enum NBodySet {
+    /// Forces besides self-gravity, applied as changes to Velocity before the
+    /// step.  Self-gravity is evaluated within Integrate, as multi-stage
+    /// schemes need it at intermediate states, so these kicks bypass the
+    /// integrator and are only first order.  Forces that depend on position
+    /// belong in the `ExternalFields` resource instead, which is integrated
+    /// along with gravity.
+    Forces,
+    /// Advancing the bodies by one step
+    Integrate,
+    /// Detecting and resolving overlapping bodies
+    Collisions,
+    /// Copying the simulation state to what is drawn
+    SyncTransforms,
+    /// Measuring and reporting the state of the simulation
+    Diagnostics,
+}
+
+impl Plugin for NBodyPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<UnitSystem>();
+        if !app.world.contains_resource::<GravityConfig>() {
+            let units = *app.world.resource::<UnitSystem>();
+            app.insert_resource(GravityConfig::for_units(&units));
+        }
+        app.init_resource::<LinearOctree>()
+            .init_resource::<ExternalFields>()
+            .init_resource::<Scheme>()
+            .init_resource::<Collisions>()
+            .init_resource::<CollisionResponse>()
+            .init_resource::<FixedTimestep>()
+            .add_event::<CollisionEvent>()
+            .init_schedule(PhysicsSchedule)
+            .edit_schedule(PhysicsSchedule, |schedule| {
+                schedule.configure_sets((NBodySet::Forces, NBodySet::Integrate, NBodySet::Collisions).chain());
+            })
+            .configure_sets((NBodySet::SyncTransforms, NBodySet::Diagnostics).chain())
+            .configure_set(NBodySet::SyncTransforms.after(timestep::run_physics_schedule))
+            .add_startup_system(log_units_system)
+            .add_system(hermite_solver_warning_system)
+            .add_startup_system(initial_acceleration_system
+                .in_base_set(StartupSet::PostStartup)
+                .before(ConservationDiagnosticsPlugin::diagnostic_system))
+            .add_systems((previous_position_system, integrate_system)
+                .chain()
+                .in_set(NBodySet::Integrate)
+                .in_schedule(PhysicsSchedule))
+            .add_systems((
+                    collisions::collision_event_system,
+                    collisions::merge_system,
+                    collisions::bounce_system,
+                    apply_system_buffers,
+                    collisions::refresh_acceleration_system,
+                ).chain()
+                .in_set(NBodySet::Collisions)
+                .in_schedule(PhysicsSchedule));
+    }
+}
+
+fn log_units_system(units: Res<UnitSystem>, config: Res<GravityConfig>)
+{
+    let (length, mass, time) = units.names();
+    info!("Simulating in {:?} units ({}, {}, {}), G = {:e} {}^3 {}^-1 {}^-2",
+        *units, length, mass, time, config.g, length, mass, time);
+}
+
+/// Warn when Hermite runs with a tree solver, whose jerks come from direct
+/// summation anyway, at O(N^2) cost
+fn hermite_solver_warning_system(scheme: Res<Scheme>, config: Res<GravityConfig>) {
+    if !scheme.is_changed() && !config.is_changed() {
+        return;
+    }
+    if *scheme == Scheme::Hermite && config.solver != Solver::Direct {
+        warn!("Hermite takes its accelerations and jerks from direct summation, O(N^2), not the {:?} solver", config.solver);
+    }
+}
+
+/// Evaluate the accelerations of the initial conditions, which the first step
+/// of the integrators relies on
+fn initial_acceleration_system(
+    config: Res<GravityConfig>,
+    mut octree: ResMut<LinearOctree>,
+    mut q: Query<(Entity, &Position, &Mass, &Radius, &mut Acceleration)>,
+    fields: Res<ExternalFields>,
+    mut potentials: Query<&mut Potential>,
+) {
+    let bodies: Vec<NBody> = q.iter().map(|(e,p,m,r,_)| NBody::new(e,p.0,m.0,r.0)).collect();
+    let mut forces = Gravity::new(&config, &mut octree).with_fields(&fields);
+    let accelerations = forces.accelerations(&bodies);
+
+    for ((_,_,_,_,mut accel), newaccel) in q.iter_mut().zip(accelerations) {
+        accel.0 = newaccel;
+    }
+    update_potentials(&bodies, &forces.potentials, &mut potentials);
+}
+
+/// Copy the potentials of the last evaluation, if it covered every body
+fn update_potentials(bodies: &[NBody], values: &[f32], q: &mut Query<&mut Potential>) {
+    if values.len() != bodies.len() {
+        return;
+    }
+    for (body, value) in bodies.iter().zip(values) {
+        if let Ok(mut potential) = q.get_mut(body.entity) {
+            potential.0 = *value;
+        }
+    }
+}
+
+/// Advance every body by one step of the selected integration scheme
+#[allow(clippy::too_many_arguments)]
+fn integrate_system(
+    timestep: Res<FixedTimestep>,
+    config: Res<GravityConfig>,
+    scheme: Res<Scheme>,
+    mut octree: ResMut<LinearOctree>,
+    fields: Res<ExternalFields>,
+    mut q: Query<(Entity, &mut Position, &mut Velocity, &mut Acceleration, &Mass, &Radius)>,
+    mut potentials: Query<&mut Potential>,
+    mut collisions: ResMut<Collisions>,
+) {
+    let mut state = State {
+        bodies: q.iter().map(|(e,p,_,_,m,r)| NBody::new(e,p.0,m.0,r.0)).collect(),
+        velocities: q.iter().map(|(_,_,v,_,_,_)| v.0).collect(),
+        accelerations: q.iter().map(|(_,_,_,a,_,_)| a.0).collect(),
+    };
+
+    let dt = timestep.step();
+    let mut forces = Gravity::new(&config, &mut octree).with_fields(&fields);
+    scheme.integrator().step(&mut state, dt, &mut forces);
+    update_potentials(&state.bodies, &forces.potentials, &mut potentials);
+    *collisions = Collisions::from_lists(&state.bodies, &forces.collisions);
+
+    let updated = state.bodies.iter().zip(state.velocities).zip(state.accelerations);
+    for ((_,mut position,mut velocity,mut accel,_,_), ((body, newvel), newaccel)) in q.iter_mut().zip(updated) {
+        position.0 = body.position;
+        velocity.0 = newvel;
+        accel.0 = newaccel;
+    }
+}
+
+/// Remember where each body was before the step, for interpolation
+fn previous_position_system(
+    mut q: Query<(&mut PreviousPosition, &Position)>,
+) {
+    for (mut previous, position) in q.iter_mut() {
+        previous.0 = position.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Resource,Default)]
+    struct Order(Vec<NBodySet>);
+
+    fn record(set: NBodySet) -> impl FnMut(ResMut<Order>) {
+        move |mut order: ResMut<Order>| order.0.push(set)
+    }
+
+    #[test]
+    fn physics_sets_run_in_order() {
+        let mut app = App::new();
+        app.add_plugin(NBodyPlugin).init_resource::<Order>();
+        // Added in reverse, so only the set ordering can put them right
+        for set in [NBodySet::Collisions, NBodySet::Integrate, NBodySet::Forces] {
+            app.add_system(record(set).in_set(set).in_schedule(PhysicsSchedule));
+        }
+
+        app.world.run_schedule(PhysicsSchedule);
+        assert_eq!(app.world.resource::<Order>().0, [NBodySet::Forces, NBodySet::Integrate, NBodySet::Collisions]);
+    }
+
+    #[test]
+    fn gravity_defaults_to_the_unit_system() {
+        let mut app = App::new();
+        app.insert_resource(UnitSystem::NBody).add_plugin(NBodyPlugin);
+        let config = app.world.resource::<GravityConfig>();
+        assert!((config.g - 1.0).abs() < 1e-6, "{}", config.g);
+        assert_eq!(config.softening_length(), GravityConfig::for_units(&UnitSystem::NBody).softening_length());
+    }
+
+    /// A lone body in a uniform field accelerates uniformly
+    #[test]
+    fn external_fields_are_integrated() {
+        let mut app = App::new();
+        app.add_plugin(NBodyPlugin);
+        app.world.resource_mut::<ExternalFields>().add(|_| Vec3::X);
+        let body = app.world.spawn((
+            Mass(1.0), Radius(0.1), Position(Vec3::ZERO), PreviousPosition(Vec3::ZERO),
+            Velocity(Vec3::ZERO), Acceleration(Vec3::X), Potential(0.0),
+        )).id();
+
+        for _ in 0..10 {
+            app.world.run_schedule(PhysicsSchedule);
+        }
+
+        let t = 10.0 * app.world.resource::<FixedTimestep>().step();
+        let body = app.world.entity(body);
+        assert!((body.get::<Velocity>().unwrap().0 - Vec3::X * t).length() < 1e-6);
+        assert!((body.get::<Position>().unwrap().0 - Vec3::X * 0.5 * t * t).length() < 1e-6);
+    }
+}
@@ -0,0 +1,546 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use bevy::prelude::*;
+use rand::prelude::*;
+use ron::extensions::Extensions;
+use serde::Deserialize;
+
+use crate::gravity::Solver;
+use crate::initial_conditions;
+use crate::integrator::Scheme;
+use crate::units::UnitSystem;
+
+// Scenario files
+//
+// A scenario describes the initial conditions of a run, and optionally how to
+// run and view it, in RON.  Every quantity in a file is in the unit system the
+// file names, and is converted into the one the simulation runs in, so the
+// same file can be run in any of them.  Files are checked after parsing, and
+// errors name the offending field, e.g. `generators[1].count: must be at
+// least 1`.  Vectors are written as tuples, `(x, y, z)`, and optional fields
+// may be given without `Some(...)`.  See the examples in scenarios/.
+
+/// Initial conditions and run settings, as read from a scenario file
+#[derive(Resource,Clone,Debug,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    /// Unit system of the quantities in the scenario: si, astronomical or
+    /// nbody
+    #[serde(default = "default_units")]
+    pub units: String,
+    /// Integration scheme, by name
+    #[serde(default)]
+    pub integrator: Option<String>,
+    /// Gravity solver, by name
+    #[serde(default)]
+    pub solver: Option<String>,
+    #[serde(default)]
+    pub timestep: Option<TimestepSpec>,
+    #[serde(default)]
+    pub camera: Option<CameraSpec>,
+    /// Seed of the generators' random numbers.  A different sequence every
+    /// run if not given.
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// Individual bodies
+    #[serde(default)]
+    pub bodies: Vec<BodySpec>,
+    /// Groups of bodies made by the generators in `initial_conditions`
+    #[serde(default)]
+    pub generators: Vec<Generator>,
+}
+
+fn default_units() -> String {
+    "astronomical".to_string()
+}
+
+#[derive(Clone,Debug,Default,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimestepSpec {
+    /// Simulated time per physics step, the integration timestep.  With the
+    /// time scale it sets how many steps run per wall clock second.
+    #[serde(default)]
+    pub dt: Option<f32>,
+    /// Simulated time per wall clock second
+    #[serde(default)]
+    pub time_scale: Option<f32>,
+    /// Maximum number of physics steps run in one frame
+    #[serde(default)]
+    pub max_substeps: Option<u32>,
+}
+
+#[derive(Clone,Debug,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CameraSpec {
+    /// Point in the middle of the view
+    #[serde(default)]
+    pub center: [f32; 2],
+    /// Zoom, as the projection scale
+    #[serde(default)]
+    pub scale: Option<f32>,
+}
+
+#[derive(Clone,Debug,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BodySpec {
+    pub mass: f32,
+    pub position: [f32; 3],
+    #[serde(default)]
+    pub velocity: [f32; 3],
+}
+
+/// A group of bodies, moved to `center` and given the bulk `velocity`
+#[derive(Clone,Debug,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum Generator {
+    /// A central mass with a ring of bodies on circular orbits
+    Disk {
+        central_mass: f32,
+        count: usize,
+        radius: f32,
+        #[serde(default)]
+        center: [f32; 3],
+        #[serde(default)]
+        velocity: [f32; 3],
+    },
+    /// A Plummer sphere in virial equilibrium
+    Sphere {
+        total_mass: f32,
+        count: usize,
+        scale_radius: f32,
+        #[serde(default)]
+        center: [f32; 3],
+        #[serde(default)]
+        velocity: [f32; 3],
+    },
+    /// Bodies of equal mass at rest on a regular grid
+    Grid {
+        mass: f32,
+        counts: [usize; 3],
+        spacing: f32,
+        #[serde(default)]
+        center: [f32; 3],
+        #[serde(default)]
+        velocity: [f32; 3],
+    },
+    /// Bodies scattered uniformly in a box
+    Random {
+        count: usize,
+        /// Smallest and largest mass
+        masses: [f32; 2],
+        half_extents: [f32; 3],
+        #[serde(default)]
+        max_speed: f32,
+        #[serde(default)]
+        center: [f32; 3],
+        #[serde(default)]
+        velocity: [f32; 3],
+    },
+}
+
+/// Reasons a scenario cannot be used
+#[derive(Debug)]
+pub enum ScenarioError {
+    Io(io::Error),
+    Parse(ron::error::SpannedError),
+    /// A field with a value out of range
+    Invalid { field: String, message: String },
+}
+
+impl fmt::Display for ScenarioError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScenarioError::Io(err) => write!(f, "{}", err),
+            ScenarioError::Parse(err) => write!(f, "{}", err),
+            ScenarioError::Invalid { field, message } => write!(f, "{}: {}", field, message),
+        }
+    }
+}
+
+impl std::error::Error for ScenarioError {}
+
+fn invalid(field: impl Into<String>, message: impl Into<String>) -> ScenarioError {
+    ScenarioError::Invalid { field: field.into(), message: message.into() }
+}
+
+fn positive(field: impl Into<String>, value: f32) -> Result<(), ScenarioError> {
+    if value > 0.0 && value.is_finite() {
+        Ok(())
+    } else {
+        Err(invalid(field, format!("must be positive, not {}", value)))
+    }
+}
+
+fn non_negative(field: impl Into<String>, value: f32) -> Result<(), ScenarioError> {
+    if value >= 0.0 && value.is_finite() {
+        Ok(())
+    } else {
+        Err(invalid(field, format!("must not be negative, not {}", value)))
+    }
+}
+
+fn finite(field: impl Into<String>, value: [f32; 3]) -> Result<(), ScenarioError> {
+    if value.iter().all(|x| x.is_finite()) {
+        Ok(())
+    } else {
+        Err(invalid(field, format!("must be finite, not {:?}", value)))
+    }
+}
+
+fn at_least_one(field: impl Into<String>, count: usize) -> Result<(), ScenarioError> {
+    if count >= 1 {
+        Ok(())
+    } else {
+        Err(invalid(field, "must be at least 1"))
+    }
+}
+
+impl Default for Scenario {
+    /// A ring of 10000 bodies at 1 AU around a star of one solar mass
+    fn default() -> Self {
+        Scenario {
+            units: default_units(),
+            integrator: None,
+            solver: None,
+            timestep: None,
+            camera: None,
+            seed: None,
+            bodies: Vec::new(),
+            generators: vec![Generator::Disk {
+                central_mass: 1.0,
+                count: 10000,
+                radius: 1.0,
+                center: [0.0; 3],
+                velocity: [0.0; 3],
+            }],
+        }
+    }
+}
+
+impl FromStr for Scenario {
+    type Err = ScenarioError;
+
+    /// Parse and validate a scenario
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let options = ron::Options::default().with_default_extension(Extensions::IMPLICIT_SOME);
+        let scenario: Scenario = options.from_str(s).map_err(ScenarioError::Parse)?;
+        scenario.validate()?;
+        Ok(scenario)
+    }
+}
+
+impl Scenario {
+
+    /// Read, parse and validate a scenario file
+    pub fn load(path: &Path) -> Result<Self, ScenarioError> {
+        fs::read_to_string(path).map_err(ScenarioError::Io)?.parse()
+    }
+
+    /// Check every field is in range
+    pub fn validate(&self) -> Result<(), ScenarioError> {
+        self.unit_system()?;
+        self.scheme()?;
+        self.solver()?;
+
+        if let Some(timestep) = &self.timestep {
+            if let Some(dt) = timestep.dt {
+                positive("timestep.dt", dt)?;
+            }
+            if let Some(time_scale) = timestep.time_scale {
+                positive("timestep.time_scale", time_scale)?;
+            }
+            if timestep.max_substeps == Some(0) {
+                return Err(invalid("timestep.max_substeps", "must be at least 1"));
+            }
+        }
+        if let Some(camera) = &self.camera {
+            finite("camera.center", [camera.center[0], camera.center[1], 0.0])?;
+            if let Some(scale) = camera.scale {
+                positive("camera.scale", scale)?;
+            }
+        }
+
+        for (i, body) in self.bodies.iter().enumerate() {
+            positive(format!("bodies[{}].mass", i), body.mass)?;
+            finite(format!("bodies[{}].position", i), body.position)?;
+            finite(format!("bodies[{}].velocity", i), body.velocity)?;
+        }
+
+        for (i, generator) in self.generators.iter().enumerate() {
+            let field = |name: &str| format!("generators[{}].{}", i, name);
+            match generator {
+                Generator::Disk { central_mass, count, radius, .. } => {
+                    positive(field("central_mass"), *central_mass)?;
+                    at_least_one(field("count"), *count)?;
+                    positive(field("radius"), *radius)?;
+                },
+                Generator::Sphere { total_mass, count, scale_radius, .. } => {
+                    positive(field("total_mass"), *total_mass)?;
+                    at_least_one(field("count"), *count)?;
+                    positive(field("scale_radius"), *scale_radius)?;
+                },
+                Generator::Grid { mass, counts, spacing, .. } => {
+                    positive(field("mass"), *mass)?;
+                    for (axis, count) in counts.iter().enumerate() {
+                        at_least_one(format!("{}[{}]", field("counts"), axis), *count)?;
+                    }
+                    positive(field("spacing"), *spacing)?;
+                },
+                Generator::Random { count, masses, half_extents, max_speed, .. } => {
+                    at_least_one(field("count"), *count)?;
+                    positive(field("masses[0]"), masses[0])?;
+                    positive(field("masses[1]"), masses[1])?;
+                    if masses[1] < masses[0] {
+                        return Err(invalid(field("masses"), "largest mass is below the smallest"));
+                    }
+                    for (axis, half) in half_extents.iter().enumerate() {
+                        non_negative(format!("{}[{}]", field("half_extents"), axis), *half)?;
+                    }
+                    non_negative(field("max_speed"), *max_speed)?;
+                },
+            }
+            let (center, velocity) = generator.bulk_motion();
+            finite(field("center"), center)?;
+            finite(field("velocity"), velocity)?;
+        }
+
+        if self.bodies.is_empty() && self.generators.is_empty() {
+            return Err(invalid("bodies", "the scenario has no bodies or generators"));
+        }
+        Ok(())
+    }
+
+    /// The unit system the quantities in the scenario are in
+    pub fn unit_system(&self) -> Result<UnitSystem, ScenarioError> {
+        self.units.parse().map_err(|err| invalid("units", err))
+    }
+
+    /// The integration scheme, if the scenario names one
+    pub fn scheme(&self) -> Result<Option<Scheme>, ScenarioError> {
+        self.integrator.as_ref()
+            .map(|name| name.parse().map_err(|err| invalid("integrator", err)))
+            .transpose()
+    }
+
+    /// The gravity solver, if the scenario names one
+    pub fn solver(&self) -> Result<Option<Solver>, ScenarioError> {
+        self.solver.as_ref()
+            .map(|name| name.parse().map_err(|err| invalid("solver", err)))
+            .transpose()
+    }
+
+    /// Set the number of bodies of every disk, sphere and random generator.
+    /// Fails if there are none.
+    pub fn set_count(&mut self, n: usize) -> Result<(), ScenarioError> {
+        let mut found = false;
+        for generator in &mut self.generators {
+            match generator {
+                Generator::Disk { count, .. }
+                | Generator::Sphere { count, .. }
+                | Generator::Random { count, .. } => {
+                    *count = n;
+                    found = true;
+                },
+                Generator::Grid { .. } => {},
+            }
+        }
+        found.then_some(()).ok_or_else(|| invalid("generators", "there is no disk, sphere or random generator to set the count of"))
+    }
+
+    /// Set the central mass of every disk.  Fails if there are none.
+    pub fn set_central_mass(&mut self, mass: f32) -> Result<(), ScenarioError> {
+        self.each_disk(|central_mass, _| *central_mass = mass)
+    }
+
+    /// Set the radius of every disk.  Fails if there are none.
+    pub fn set_disk_radius(&mut self, r: f32) -> Result<(), ScenarioError> {
+        self.each_disk(|_, radius| *radius = r)
+    }
+
+    fn each_disk(&mut self, mut f: impl FnMut(&mut f32, &mut f32)) -> Result<(), ScenarioError> {
+        let mut found = false;
+        for generator in &mut self.generators {
+            if let Generator::Disk { central_mass, radius, .. } = generator {
+                f(central_mass, radius);
+                found = true;
+            }
+        }
+        found.then_some(()).ok_or_else(|| invalid("generators", "there is no disk generator"))
+    }
+
+    /// Random number generator for the generators, from the seed if given
+    pub fn rng(&self) -> StdRng {
+        match self.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        }
+    }
+
+    /// Every body of the scenario, as (mass, position, velocity) in `units`
+    pub fn bodies_in<R:Rng>(&self, rng: &mut R, units: &UnitSystem) -> Vec<(f32,Vec3,Vec3)> {
+        let own = self.unit_system().unwrap_or_default();
+        let g = own.g();
+
+        let mut bodies: Vec<(f32,Vec3,Vec3)> = self.bodies.iter()
+            .map(|b| (b.mass, Vec3::from(b.position), Vec3::from(b.velocity)))
+            .collect();
+        for generator in &self.generators {
+            let generated = match *generator {
+                Generator::Disk { central_mass, count, radius, .. } =>
+                    initial_conditions::stable_orbit_particles(rng, g, central_mass, count, radius),
+                Generator::Sphere { total_mass, count, scale_radius, .. } =>
+                    initial_conditions::plummer_sphere(rng, g, total_mass, count, scale_radius),
+                Generator::Grid { mass, counts, spacing, .. } =>
+                    initial_conditions::grid(mass, counts, spacing),
+                Generator::Random { count, masses, half_extents, max_speed, .. } =>
+                    initial_conditions::random_box(rng, count, (masses[0], masses[1]), Vec3::from(half_extents), max_speed),
+            };
+            let (center, velocity) = generator.bulk_motion();
+            let (center, velocity) = (Vec3::from(center), Vec3::from(velocity));
+            bodies.extend(generated.into_iter().map(|(m, p, v)| (m, p + center, v + velocity)));
+        }
+
+        let (length, mass, time) = conversion(&own, units);
+        bodies.into_iter()
+            .map(|(m, p, v)| (m * mass, p * length, v * (length / time)))
+            .collect()
+    }
+
+    /// Size and total mass of the scenario, in meters and kilograms: the
+    /// distance of the farthest body from the origin, and the sum of the
+    /// masses
+    pub fn extent(&self) -> (f64, f64) {
+        let own = self.unit_system().unwrap_or_default();
+        let bodies = self.bodies_in(&mut StdRng::seed_from_u64(0), &own);
+        let size = bodies.iter().map(|b| b.1.length()).fold(0.0, f32::max) as f64;
+        let mass = bodies.iter().map(|b| b.0 as f64).sum::<f64>();
+        (size * own.length_unit(), mass * own.mass_unit())
+    }
+
+    /// Simulated time per physics step in `units`, if given
+    pub fn dt_in(&self, units: &UnitSystem) -> Option<f32> {
+        let (_, _, time) = conversion(&self.unit_system().unwrap_or_default(), units);
+        self.timestep.as_ref()?.dt.map(|dt| dt * time)
+    }
+
+    /// Simulated time per wall clock second in `units`, if given
+    pub fn time_scale_in(&self, units: &UnitSystem) -> Option<f32> {
+        let (_, _, time) = conversion(&self.unit_system().unwrap_or_default(), units);
+        self.timestep.as_ref()?.time_scale.map(|t| t * time)
+    }
+
+    /// Center of the view in `units`, if given
+    pub fn camera_center_in(&self, units: &UnitSystem) -> Option<Vec2> {
+        let (length, _, _) = conversion(&self.unit_system().unwrap_or_default(), units);
+        self.camera.as_ref().map(|camera| Vec2::from(camera.center) * length)
+    }
+}
+
+impl Generator {
+    fn bulk_motion(&self) -> ([f32; 3], [f32; 3]) {
+        match *self {
+            Generator::Disk { center, velocity, .. }
+            | Generator::Sphere { center, velocity, .. }
+            | Generator::Grid { center, velocity, .. }
+            | Generator::Random { center, velocity, .. } => (center, velocity),
+        }
+    }
+}
+
+/// Factors converting lengths, masses and times from one unit system to another
+fn conversion(from: &UnitSystem, to: &UnitSystem) -> (f32, f32, f32) {
+    (
+        (from.length_unit() / to.length_unit()) as f32,
+        (from.mass_unit() / to.mass_unit()) as f32,
+        (from.time_unit() / to.time_unit()) as f32,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::units;
+
+    #[test]
+    fn examples_are_valid() {
+        for example in [
+            include_str!("../scenarios/ring.ron"),
+            include_str!("../scenarios/galaxies.ron"),
+            include_str!("../scenarios/cold_collapse.ron"),
+        ] {
+            example.parse::<Scenario>().unwrap_or_else(|err| panic!("{}", err));
+        }
+        Scenario::default().validate().unwrap();
+    }
+
+    #[test]
+    fn errors_name_the_field() {
+        let error = |s: &str| s.parse::<Scenario>().unwrap_err().to_string();
+        assert!(error("(generators: [Disk(central_mass: 1.0, count: 10, radius: 1.0), Grid(mass: 1.0, counts: (2, 0, 1), spacing: 1.0)])")
+            .starts_with("generators[1].counts[1]:"));
+        assert!(error("(integrator: \"euler\", bodies: [(mass: 1.0, position: (0.0, 0.0, 0.0))])")
+            .starts_with("integrator:"));
+        assert!(error("(solver: \"pm\", bodies: [(mass: 1.0, position: (0.0, 0.0, 0.0))])")
+            .starts_with("solver:"));
+        assert!(error("(bodies: [(mass: -1.0, position: (0.0, 0.0, 0.0))])")
+            .starts_with("bodies[0].mass:"));
+        assert!(error("(timestep: (dt: 0.0), bodies: [(mass: 1.0, position: (0.0, 0.0, 0.0))])")
+            .starts_with("timestep.dt:"));
+        assert!(error("(bodies: [(mass: 1.0, postion: (0.0, 0.0, 0.0))])").contains("postion"));
+        assert!(error("()").starts_with("bodies:"));
+    }
+
+    /// The Sun and the Earth given in SI come out in astronomical units
+    #[test]
+    fn converts_units() {
+        let scenario: Scenario = format!(
+            "(units: \"si\", timestep: (dt: {}, time_scale: {}), bodies: [(mass: {}, position: (0.0, 0.0, 0.0)), (mass: 5.97e24, position: ({}, 0.0, 0.0), velocity: (0.0, 29780.0, 0.0))])",
+            units::YEAR / 1000.0, units::YEAR, units::MSUN, units::AU).parse().unwrap();
+        let au = UnitSystem::Astronomical;
+        let bodies = scenario.bodies_in(&mut scenario.rng(), &au);
+        assert!((bodies[0].0 - 1.0).abs() < 1e-6);
+        assert!((bodies[1].1.x - 1.0).abs() < 1e-6);
+        // About 2 pi AU per year
+        assert!((bodies[1].2.y / (2.0 * std::f32::consts::PI) - 1.0).abs() < 1e-2);
+        assert!((scenario.dt_in(&au).unwrap() - 1e-3).abs() < 1e-8);
+        assert!((scenario.time_scale_in(&au).unwrap() - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn seed_repeats_bodies() {
+        let scenario: Scenario = "(seed: 7, generators: [Sphere(total_mass: 1.0, count: 100, scale_radius: 1.0, center: (5.0, 0.0, 0.0))])"
+            .parse().unwrap();
+        let units = scenario.unit_system().unwrap();
+        let first = scenario.bodies_in(&mut scenario.rng(), &units);
+        let second = scenario.bodies_in(&mut scenario.rng(), &units);
+        assert_eq!(first, second);
+        assert_eq!(first.len(), 100);
+    }
+
+    #[test]
+    fn overrides_disks() {
+        let mut scenario = Scenario::default();
+        scenario.set_count(10).unwrap();
+        scenario.set_central_mass(2.0).unwrap();
+        scenario.set_disk_radius(3.0).unwrap();
+        let units = scenario.unit_system().unwrap();
+        let bodies = scenario.bodies_in(&mut scenario.rng(), &units);
+        // The central body and the ring
+        assert_eq!(bodies.len(), 10);
+        assert_eq!(bodies[0].0, 2.0);
+        assert!(bodies[1..].iter().all(|b| (b.1.length() - 3.0).abs() <= 0.3));
+
+        scenario.set_count(0).unwrap();
+        assert!(scenario.validate().unwrap_err().to_string().starts_with("generators[0].count:"));
+
+        // Nothing to override
+        let mut grid: Scenario = "(generators: [Grid(mass: 1.0, counts: (2, 2, 1), spacing: 1.0)])".parse().unwrap();
+        assert!(grid.set_count(10).is_err());
+        assert!(grid.set_central_mass(2.0).is_err());
+        assert!(grid.set_disk_radius(3.0).is_err());
+    }
+}
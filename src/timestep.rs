@@ -0,0 +1,154 @@
+use bevy::ecs::schedule::ScheduleLabel;
+use bevy::prelude::*;
+
+// Fixed simulation timestep
+//
+// Frame time is added to an accumulator, and the physics schedule runs once
+// for every whole step in it, so trajectories do not depend on the frame rate.
+// After a hiccup at most `max_substeps` steps run in one frame and the rest of
+// the backlog is dropped, so the simulation slows down instead of spiralling.
+// Whatever is left in the accumulator is the fraction of the way to the next
+// physics state, which rendering uses to interpolate.  A run may be limited to
+// a number of steps, after which no more are taken however many frames pass.
+
+/// Schedule holding the systems that advance the simulation by one step
+#[derive(ScheduleLabel,Clone,Debug,PartialEq,Eq,Hash)]
+pub struct PhysicsSchedule;
+
+/// Fixed timestep settings and accumulator
+#[derive(Resource,Clone,Copy,Debug)]
+pub struct FixedTimestep {
+    /// Wall clock seconds per physics step
+    pub dt: f32,
+    /// Maximum number of physics steps run in one frame
+    pub max_substeps: u32,
+    /// Simulated time per wall clock second, in the time unit of the unit
+    /// system
+    pub time_scale: f32,
+    /// Physics steps left to run, if the run is limited
+    pub steps_left: Option<u64>,
+    /// Frame time not yet consumed by physics steps
+    accumulator: f32,
+}
+
+impl FixedTimestep {
+    pub fn new(dt: f32, max_substeps: u32, time_scale: f32) -> Self {
+        FixedTimestep { dt, max_substeps, time_scale, steps_left: None, accumulator: 0.0 }
+    }
+
+    /// Simulated time advanced by each physics step
+    pub fn step(&self) -> f32 {
+        self.dt * self.time_scale
+    }
+
+    /// How far the current frame is between the previous and the current
+    /// physics state, in [0, 1)
+    pub fn alpha(&self) -> f32 {
+        (self.accumulator / self.dt).clamp(0.0, 1.0)
+    }
+}
+
+impl Default for FixedTimestep {
+    fn default() -> Self {
+        FixedTimestep::new(1.0 / 60.0, 4, 1.0)
+    }
+}
+
+/// Run the physics schedule for as many whole steps as have accumulated
+pub fn run_physics_schedule(world: &mut World) {
+    let delta = world.resource::<Time>().delta_seconds();
+    let mut timestep = world.resource_mut::<FixedTimestep>();
+    timestep.accumulator += delta;
+
+    let mut substeps = 0;
+    loop {
+        let mut timestep = world.resource_mut::<FixedTimestep>();
+        if timestep.steps_left == Some(0) {
+            // Stay on the last state
+            timestep.accumulator = 0.0;
+            break;
+        }
+        if timestep.accumulator < timestep.dt {
+            break;
+        }
+        if substeps == timestep.max_substeps {
+            timestep.accumulator %= timestep.dt;
+            break;
+        }
+        timestep.accumulator -= timestep.dt;
+        if let Some(left) = &mut timestep.steps_left {
+            *left -= 1;
+        }
+        substeps += 1;
+        world.run_schedule(PhysicsSchedule);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    #[derive(Resource,Default)]
+    struct Steps(u32);
+
+    fn count_steps(mut steps: ResMut<Steps>) {
+        steps.0 += 1;
+    }
+
+    /// Advance the clock by `seconds` and run one frame, returning the number
+    /// of physics steps taken
+    fn frame(world: &mut World, now: &mut Instant, seconds: f32) -> u32 {
+        *now += Duration::from_secs_f32(seconds);
+        world.resource_mut::<Time>().update_with_instant(*now);
+        world.resource_mut::<Steps>().0 = 0;
+        run_physics_schedule(world);
+        world.resource::<Steps>().0
+    }
+
+    fn world() -> (World, Instant) {
+        let mut world = World::new();
+        world.init_resource::<Schedules>();
+        let mut schedule = Schedule::new();
+        schedule.add_system(count_steps);
+        world.add_schedule(schedule, PhysicsSchedule);
+        world.init_resource::<Steps>();
+        world.insert_resource(FixedTimestep::new(1.0 / 64.0, 4, 1.0));
+
+        let now = Instant::now();
+        let mut time = Time::default();
+        time.update_with_instant(now);
+        world.insert_resource(time);
+        (world, now)
+    }
+
+    #[test]
+    fn steps_do_not_depend_on_frame_rate() {
+        let (mut world, mut now) = world();
+        // Two and a half steps per frame
+        let total: u32 = (0..100).map(|_| frame(&mut world, &mut now, 2.5 / 64.0)).sum();
+        assert_eq!(total, 250);
+        assert_eq!(world.resource::<FixedTimestep>().alpha(), 0.0);
+    }
+
+    #[test]
+    fn hiccup_is_capped() {
+        let (mut world, mut now) = world();
+        assert_eq!(frame(&mut world, &mut now, 1.0), 4);
+        assert!(world.resource::<FixedTimestep>().alpha() < 1.0);
+        assert_eq!(frame(&mut world, &mut now, 1.0 / 64.0), 1);
+    }
+
+    #[test]
+    fn step_limit_is_exact() {
+        for limit in [0, 1, 5] {
+            let (mut world, mut now) = world();
+            world.resource_mut::<FixedTimestep>().steps_left = Some(limit);
+            // Two and a half steps per frame
+            let total: u32 = (0..10).map(|_| frame(&mut world, &mut now, 2.5 / 64.0)).sum();
+            assert_eq!(total as u64, limit);
+            assert_eq!(world.resource::<FixedTimestep>().steps_left, Some(0));
+        }
+    }
+}
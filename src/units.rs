@@ -0,0 +1,193 @@
+use std::str::FromStr;
+
+use bevy::prelude::*;
+
+// Unit systems
+//
+// Every quantity in the simulation is a plain f32 in the selected unit system.
+// Physical constants are kept here in SI, and scenarios convert whatever they
+// specify in SI through the unit system on the way in.  Note that f32 cannot
+// hold the quadrupole moments (mass times length squared) of astronomical
+// bodies in SI, nor G / r^3 at their distances, so SI only suits scenes on
+// human scales.  `check_scale` rejects scenes a unit system cannot hold.
+
+/// Newtonian gravitational constant, m^3 kg^-1 s^-2 (CODATA 2018)
+pub const G: f64 = 6.674_30e-11;
+/// Astronomical unit, m (IAU 2012)
+pub const AU: f64 = 1.495_978_707e11;
+/// Solar mass, kg
+pub const MSUN: f64 = 1.988_47e30;
+/// Julian year, s
+pub const YEAR: f64 = 365.25 * 86_400.0;
+
+/// Largest mass times length squared a scene may have, well below f32::MAX
+const MAX_MOMENT: f64 = 1e30;
+/// Smallest mass, and mass times length squared, a scene may have, well above
+/// f32::MIN_POSITIVE
+const MIN_MASS: f64 = 1e-30;
+const MIN_MOMENT: f64 = 1e-30;
+/// Smallest G / r^3 a scene may need, well above f32::MIN_POSITIVE
+const MIN_TIDE: f64 = 1e-30;
+
+/// Units the simulation state is expressed in
+#[derive(Resource,Clone,Copy,Debug,Default,PartialEq,Eq)]
+pub enum UnitSystem {
+    /// Meters, kilograms and seconds
+    SI,
+    /// Astronomical units, solar masses and years, where G is about 4 pi^2
+    #[default]
+    Astronomical,
+    /// Astronomical units and solar masses, with the time unit chosen so
+    /// that G = 1 (one year is 2 pi)
+    NBody,
+}
+
+impl UnitSystem {
+
+    /// Length unit, in meters
+    pub fn length_unit(&self) -> f64 {
+        match self {
+            UnitSystem::SI => 1.0,
+            UnitSystem::Astronomical | UnitSystem::NBody => AU,
+        }
+    }
+
+    /// Mass unit, in kilograms
+    pub fn mass_unit(&self) -> f64 {
+        match self {
+            UnitSystem::SI => 1.0,
+            UnitSystem::Astronomical | UnitSystem::NBody => MSUN,
+        }
+    }
+
+    /// Time unit, in seconds
+    pub fn time_unit(&self) -> f64 {
+        match self {
+            UnitSystem::SI => 1.0,
+            UnitSystem::Astronomical => YEAR,
+            UnitSystem::NBody => (AU * AU * AU / (G * MSUN)).sqrt(),
+        }
+    }
+
+    /// Gravitational constant in this unit system
+    pub fn g(&self) -> f32 {
+        match self {
+            UnitSystem::NBody => 1.0,
+            _ => {
+                let t = self.time_unit();
+                (G * self.mass_unit() * t * t / self.length_unit().powi(3)) as f32
+            },
+        }
+    }
+
+    /// Convert a length in meters into this unit system
+    pub fn length(&self, meters: f64) -> f32 {
+        (meters / self.length_unit()) as f32
+    }
+
+    /// Convert a mass in kilograms into this unit system
+    pub fn mass(&self, kilograms: f64) -> f32 {
+        (kilograms / self.mass_unit()) as f32
+    }
+
+    /// Convert a time in seconds into this unit system
+    pub fn time(&self, seconds: f64) -> f32 {
+        (seconds / self.time_unit()) as f32
+    }
+
+    /// Convert a density in kg m^-3 into this unit system
+    pub fn density(&self, kg_per_m3: f64) -> f32 {
+        (kg_per_m3 * self.length_unit().powi(3) / self.mass_unit()) as f32
+    }
+
+    /// Check that a scene of the given size and total mass, in meters and
+    /// kilograms, fits in f32 in this unit system: its quadrupole moments must
+    /// stay finite, and its mass, quadrupole moments and G / size^3 must stay
+    /// normal numbers, each with a wide margin for sums over many bodies
+    pub fn check_scale(&self, size: f64, mass: f64) -> Result<(), String> {
+        let (length, mass) = (size / self.length_unit(), mass / self.mass_unit());
+        let (length_name, mass_name, _) = self.names();
+        let moment = mass * length * length;
+        if moment > MAX_MOMENT {
+            return Err(format!("a scene of {:e} {} and {:e} {} overflows f32 in {:?} units",
+                length, length_name, mass, mass_name, self));
+        }
+        if mass > 0.0 && mass < MIN_MASS {
+            return Err(format!("a scene of {:e} {} underflows f32 in {:?} units",
+                mass, mass_name, self));
+        }
+        if moment > 0.0 && moment < MIN_MOMENT {
+            return Err(format!("a scene of {:e} {} and {:e} {} underflows f32 in {:?} units",
+                length, length_name, mass, mass_name, self));
+        }
+        if length > 0.0 && (self.g() as f64) / length.powi(3) < MIN_TIDE {
+            return Err(format!("G / r^3 underflows f32 in {:?} units at {:e} {}",
+                self, length, length_name));
+        }
+        Ok(())
+    }
+
+    /// Names of the length, mass and time units, for display
+    pub fn names(&self) -> (&'static str, &'static str, &'static str) {
+        match self {
+            UnitSystem::SI => ("m", "kg", "s"),
+            UnitSystem::Astronomical => ("AU", "Msun", "yr"),
+            UnitSystem::NBody => ("AU", "Msun", "yr/2pi"),
+        }
+    }
+}
+
+impl FromStr for UnitSystem {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "si" => Ok(UnitSystem::SI),
+            "astronomical" | "au" => Ok(UnitSystem::Astronomical),
+            "nbody" | "n-body" => Ok(UnitSystem::NBody),
+            _ => Err(format!("unknown unit system '{}', expected si, astronomical or nbody", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::*;
+
+    #[test]
+    fn gravitational_constant() {
+        assert_eq!(UnitSystem::SI.g(), 6.674_30e-11);
+        assert!((UnitSystem::Astronomical.g() / (4.0 * PI * PI) - 1.0).abs() < 1e-3);
+        let t = UnitSystem::NBody.time_unit();
+        assert!((G * MSUN * t * t / (AU * AU * AU) - 1.0).abs() < 1e-12);
+    }
+
+    /// A circular orbit of 1 AU around the Sun takes one year in every system
+    #[test]
+    fn earth_orbit_takes_a_year() {
+        for units in [UnitSystem::SI, UnitSystem::Astronomical, UnitSystem::NBody] {
+            let r = units.length(AU);
+            let period = 2.0 * PI * (r * r * r / (units.g() * units.mass(MSUN))).sqrt();
+            let year = units.time(YEAR);
+            assert!((period / year - 1.0).abs() < 1e-3, "{:?}: {} vs {}", units, period, year);
+        }
+    }
+
+    #[test]
+    fn si_only_holds_small_scenes() {
+        assert!(UnitSystem::SI.check_scale(AU, MSUN).is_err());
+        assert!(UnitSystem::SI.check_scale(10.0, 1000.0).is_ok());
+        assert!(UnitSystem::Astronomical.check_scale(AU, MSUN).is_ok());
+        assert!(UnitSystem::NBody.check_scale(100.0 * AU, 1e6 * MSUN).is_ok());
+    }
+
+    #[test]
+    fn astronomical_units_underflow_small_scenes() {
+        assert!(UnitSystem::Astronomical.check_scale(0.0, 1.0).is_err());
+        assert!(UnitSystem::Astronomical.check_scale(10.0, 1000.0).is_err());
+        assert!(UnitSystem::Astronomical.check_scale(0.0, MSUN).is_ok());
+        assert!(UnitSystem::SI.check_scale(0.0, 1.0).is_ok());
+    }
+}
@@ -0,0 +1,236 @@
+use std::fmt;
+
+use bevy::prelude::*;
+
+use crate::bhtree::NBody;
+use crate::forces::{Forces, Gravity};
+use crate::gravity::{GravityConfig, Solver};
+use crate::linear_octree::LinearOctree;
+
+// Force accuracy validation
+//
+// Compares the accelerations from the approximate solvers against exact
+// direct summation over the same bodies, and summarizes the per-body relative
+// errors |a - a_exact| / |a_exact|.
+
+/// Summary of per-body relative acceleration errors
+#[derive(Clone,Copy,Debug)]
+pub struct ErrorStats {
+    pub median: f32,
+    pub p99: f32,
+    pub max: f32,
+}
+
+impl ErrorStats {
+    /// Summarize a list of relative errors
+    pub fn from(mut errors: Vec<f32>) -> Self {
+        if errors.is_empty() {
+            return ErrorStats { median: 0.0, p99: 0.0, max: 0.0 };
+        }
+        errors.sort_by(f32::total_cmp);
+        let at = |q: f32| errors[((errors.len() - 1) as f32 * q).round() as usize];
+        ErrorStats { median: at(0.5), p99: at(0.99), max: at(1.0) }
+    }
+}
+
+impl fmt::Display for ErrorStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "median {:.2e}  99% {:.2e}  max {:.2e}", self.median, self.p99, self.max)
+    }
+}
+
+/// Accelerations of every body, in the same order as `bodies`, using the
+/// solver selected in `config`
+pub fn accelerations(bodies: &[NBody], config: &GravityConfig) -> Vec<Vec3> {
+    Gravity::new(config, &mut LinearOctree::default()).accelerations(bodies)
+}
+
+/// Potential at every body, in the same order as `bodies`, using the solver
+/// selected in `config`
+pub fn potentials(bodies: &[NBody], config: &GravityConfig) -> Vec<f32> {
+    let config = GravityConfig { potential: true, ..*config };
+    let mut octree = LinearOctree::default();
+    let mut forces = Gravity::new(&config, &mut octree);
+    forces.accelerations(bodies);
+    forces.potentials
+}
+
+/// Relative error of each approximate acceleration against its exact value.
+/// Bodies with no net exact acceleration are skipped.
+pub fn relative_errors(approx: &[Vec3], exact: &[Vec3]) -> Vec<f32> {
+    approx.iter()
+        .zip(exact)
+        .filter(|(_,e)| e.length_squared() > 0.0)
+        .map(|(a,e)| (*a - *e).length() / e.length())
+        .collect()
+}
+
+/// Error of the solver selected in `config` against direct summation
+pub fn force_errors(bodies: &[NBody], config: &GravityConfig) -> ErrorStats {
+    let exact = accelerations(bodies, &GravityConfig { solver: Solver::Direct, ..*config });
+    let approx = accelerations(bodies, config);
+    ErrorStats::from(relative_errors(&approx, &exact))
+}
+
+/// Error of the potentials from the solver selected in `config` against
+/// direct summation.  Bodies with no exact potential are skipped.
+pub fn potential_errors(bodies: &[NBody], config: &GravityConfig) -> ErrorStats {
+    let exact = potentials(bodies, &GravityConfig { solver: Solver::Direct, ..*config });
+    let approx = potentials(bodies, config);
+    ErrorStats::from(approx.iter()
+        .zip(exact)
+        .filter(|(_,e)| *e != 0.0)
+        .map(|(a,e)| ((a - e) / e).abs())
+        .collect())
+}
+
+/// Error of the solver selected in `config` against direct summation, for
+/// each opening angle in `thetas`
+pub fn error_vs_theta(bodies: &[NBody], config: &GravityConfig, thetas: &[f32]) -> Vec<(f32,ErrorStats)> {
+    let exact = accelerations(bodies, &GravityConfig { solver: Solver::Direct, ..*config });
+    thetas.iter()
+        .map(|&theta| {
+            let approx = accelerations(bodies, &GravityConfig { theta, ..*config });
+            (theta, ErrorStats::from(relative_errors(&approx, &exact)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gravity::{MultipoleOrder, Softening};
+    use rand::prelude::*;
+
+    fn to_bodies(particles: &[(f32,Vec3,Vec3)]) -> Vec<NBody> {
+        particles.iter()
+            .enumerate()
+            .map(|(i,(mass,position,_))| NBody::new(Entity::from_raw(i as u32), *position, *mass, 0.0))
+            .collect()
+    }
+
+    fn plummer() -> Vec<NBody> {
+        let mut rng = StdRng::seed_from_u64(1);
+        to_bodies(&crate::initial_conditions::plummer_sphere(&mut rng, 1.0, 1.0, 4000, 1.0))
+    }
+
+    fn disk() -> Vec<NBody> {
+        let mut rng = StdRng::seed_from_u64(1);
+        to_bodies(&crate::initial_conditions::stable_orbit_particles(&mut rng, 1.0, 200000.0, 4000, 400.0))
+    }
+
+    fn config(solver: Solver, softening: f32) -> GravityConfig {
+        GravityConfig { g: 1.0, softening: Softening::Plummer(softening), solver, ..default() }
+    }
+
+    #[test]
+    fn direct_matches_itself() {
+        let bodies = plummer();
+        let stats = force_errors(&bodies, &config(Solver::Direct, 0.01));
+        assert!(stats.max < 1e-5, "{}", stats);
+    }
+
+    #[test]
+    fn barnes_hut_plummer() {
+        let stats = force_errors(&plummer(), &config(Solver::BarnesHut, 0.01));
+        assert!(stats.median < 2e-3 && stats.p99 < 1e-2 && stats.max < 5e-2, "{}", stats);
+    }
+
+    #[test]
+    fn barnes_hut_disk() {
+        let stats = force_errors(&disk(), &config(Solver::BarnesHut, 1.0));
+        assert!(stats.median < 2e-3 && stats.p99 < 1e-2 && stats.max < 5e-2, "{}", stats);
+    }
+
+    #[test]
+    fn linear_barnes_hut_plummer() {
+        let stats = force_errors(&plummer(), &config(Solver::LinearBarnesHut, 0.01));
+        assert!(stats.median < 2e-3 && stats.p99 < 1e-2 && stats.max < 5e-2, "{}", stats);
+    }
+
+    #[test]
+    fn linear_barnes_hut_disk() {
+        let stats = force_errors(&disk(), &config(Solver::LinearBarnesHut, 1.0));
+        assert!(stats.median < 2e-3 && stats.p99 < 1e-2 && stats.max < 5e-2, "{}", stats);
+    }
+
+    #[test]
+    fn fmm_plummer() {
+        let stats = force_errors(&plummer(), &config(Solver::Fmm, 0.01));
+        assert!(stats.median < 1e-2 && stats.p99 < 5e-2 && stats.max < 2e-1, "{}", stats);
+    }
+
+    /// Near the inner edge of the disk its self-gravity almost cancels the
+    /// pull of the central mass, and on the central mass itself the disk
+    /// cancels almost entirely, so relative errors there are magnified.  The
+    /// cell-cell interactions of the FMM need a tighter opening angle than
+    /// the per-body walk, and the worst case is the central mass.
+    #[test]
+    fn fmm_disk() {
+        let stats = force_errors(&disk(), &GravityConfig { theta: 0.3, ..config(Solver::Fmm, 1.0) });
+        assert!(stats.median < 1e-2 && stats.p99 < 5e-2 && stats.max < 5e-1, "{}", stats);
+    }
+
+    /// The potential converges faster with distance than the field, so every
+    /// solver is more accurate for it
+    #[test]
+    fn potentials_match_direct() {
+        let bodies = plummer();
+        for solver in [Solver::BarnesHut, Solver::LinearBarnesHut, Solver::Fmm] {
+            let stats = potential_errors(&bodies, &config(solver, 0.01));
+            assert!(stats.median < 1e-3 && stats.max < 1e-2, "{:?}: {}", solver, stats);
+        }
+    }
+
+    /// A clump of coincident or nearly coincident bodies far from the origin,
+    /// ringed by probes far enough away to see it through the multipoles of
+    /// the nodes holding it.  Returns the bodies and the probe indices.
+    fn clump(spread: i32) -> (Vec<NBody>, std::ops::Range<usize>) {
+        let mut rng = StdRng::seed_from_u64(1);
+        let center = Vec3::new(1e6, 1e6, 0.0);
+        let ulp = 0.0625;
+        let mut particles: Vec<_> = (0..5000)
+            .map(|_| {
+                let offset = IVec3::new(rng.gen_range(0..=spread), rng.gen_range(0..=spread), rng.gen_range(0..=spread));
+                (1.0, center + offset.as_vec3() * ulp, Vec3::ZERO)
+            })
+            .collect();
+        let probes = particles.len()..particles.len() + 6;
+        for direction in [Vec3::X, Vec3::NEG_X, Vec3::Y, Vec3::NEG_Y, Vec3::Z, Vec3::NEG_Z] {
+            particles.push((1.0, center + direction * 1000.0, Vec3::ZERO));
+        }
+        (to_bodies(&particles), probes)
+    }
+
+    #[test]
+    fn clumps_far_from_origin() {
+        for spread in [0, 4] {
+            let (bodies, probes) = clump(spread);
+            let exact = accelerations(&bodies, &config(Solver::Direct, 0.0));
+            for solver in [Solver::BarnesHut, Solver::LinearBarnesHut, Solver::Fmm] {
+                let approx = accelerations(&bodies, &config(solver, 0.0));
+                let stats = ErrorStats::from(relative_errors(&approx[probes.clone()], &exact[probes.clone()]));
+                assert!(stats.max < 1e-3, "{:?} spread {}: {}", solver, spread, stats);
+            }
+        }
+    }
+
+    #[test]
+    fn quadrupole_beats_monopole() {
+        let bodies = plummer();
+        let quadrupole = force_errors(&bodies, &config(Solver::BarnesHut, 0.01));
+        let monopole = force_errors(&bodies, &GravityConfig {
+            multipole_order: MultipoleOrder::Monopole,
+            ..config(Solver::BarnesHut, 0.01)
+        });
+        assert!(quadrupole.median < monopole.median, "{} vs {}", quadrupole, monopole);
+    }
+
+    #[test]
+    fn error_grows_with_theta() {
+        let errors = error_vs_theta(&plummer(), &config(Solver::BarnesHut, 0.01), &[0.3, 0.6, 1.0]);
+        for pair in errors.windows(2) {
+            assert!(pair[0].1.median < pair[1].1.median, "{:?}", errors);
+        }
+    }
+}